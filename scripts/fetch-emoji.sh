@@ -0,0 +1,14 @@
+#!/bin/sh
+# Download the 72x72 Twemoji PNGs into assets/emoji, where EMOJI_DIRECTORY looks by default
+set -eu
+
+VERSION="${TWEMOJI_VERSION:-15.1.0}"
+DEST="$(dirname "$0")/../assets/emoji"
+TMP="$(mktemp -d)"
+trap 'rm -rf "$TMP"' EXIT
+
+curl -fsSL "https://github.com/jdecked/twemoji/archive/refs/tags/v$VERSION.tar.gz" \
+    | tar -xz -C "$TMP" "twemoji-$VERSION/assets/72x72"
+mkdir -p "$DEST"
+cp "$TMP/twemoji-$VERSION/assets/72x72/"*.png "$DEST/"
+echo "Copied $(ls "$TMP/twemoji-$VERSION/assets/72x72" | wc -l) emoji images to $DEST"
@@ -0,0 +1,85 @@
+use std::path::PathBuf;
+
+use bevy::{
+    asset::{io::file::FileAssetReader, AssetServer, Handle, LoadState},
+    prelude::{Image, Query, Res, Resource},
+    utils::HashMap,
+};
+use log::warn;
+
+use crate::{chat::ChatBadge, BadgeIcon};
+
+/// Where badge images are loaded from. Badges are looked up at the badge url when one is set,
+/// falling back to the badge directory in the assets folder, eg `subscriber-12.png` or
+/// `subscriber.png`. Badges without an image are not shown
+#[derive(Resource)]
+pub(crate) struct BadgeImages {
+    /// Url with `{name}` and `{version}` replaced by the badge
+    url: Option<String>,
+    /// Image directory relative to the assets folder, used to load images with the asset server
+    asset_dir: String,
+    /// The image directory on disk
+    dir: PathBuf,
+    /// Local images looked up so far, None if there is no image for the badge
+    known: HashMap<ChatBadge, Option<String>>,
+}
+
+impl BadgeImages {
+    pub(crate) fn new(url: Option<String>, asset_dir: &str) -> Self {
+        Self {
+            url,
+            asset_dir: asset_dir.trim_end_matches('/').to_string(),
+            dir: FileAssetReader::get_base_path()
+                .join("assets")
+                .join(asset_dir),
+            known: HashMap::new(),
+        }
+    }
+
+    /// Path to load a badge's image from and the local image to use if that fails
+    pub(crate) fn source(&mut self, badge: &ChatBadge) -> Option<(String, Option<String>)> {
+        let local = self.local_path(badge);
+        match &self.url {
+            Some(url) => Some((
+                url.replace("{name}", &badge.name)
+                    .replace("{version}", &badge.version),
+                local,
+            )),
+            None => local.map(|path| (path, None)),
+        }
+    }
+
+    fn local_path(&mut self, badge: &ChatBadge) -> Option<String> {
+        if let Some(path) = self.known.get(badge) {
+            return path.clone();
+        }
+        let path = [
+            format!("{}-{}.png", badge.name, badge.version),
+            format!("{}.png", badge.name),
+        ]
+        .into_iter()
+        .find(|file| self.dir.join(file).is_file())
+        .map(|file| format!("{}/{}", self.asset_dir, file));
+        self.known.insert(badge.clone(), path.clone());
+        path
+    }
+}
+
+/// System to show the local image for badges which could not be loaded from the badge url, eg
+/// while offline
+pub(crate) fn fallback_badge_images(
+    asset_server: Res<AssetServer>,
+    mut badge_query: Query<(&mut Handle<Image>, &mut BadgeIcon)>,
+) {
+    for (mut handle, mut icon) in badge_query.iter_mut() {
+        if icon.fallback.is_none() {
+            continue;
+        }
+        if let LoadState::Failed(err) = asset_server.load_state(handle.id()) {
+            warn!("Using the local badge image: {}", err);
+            if let Some(fallback) = icon.fallback.take() {
+                *handle = asset_server.load(fallback);
+            }
+        }
+    }
+}
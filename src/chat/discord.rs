@@ -0,0 +1,589 @@
+use std::time::Duration;
+
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    net::TcpStream,
+    runtime::Handle,
+    sync::mpsc,
+    task::JoinHandle,
+    time::{interval, sleep},
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::{
+    chat::{ChatEvent, ChatMessage, ChatSource, EmoteMetaResolver, EmoteSpan, Platform},
+    config::DiscordConfig,
+    emotes::{cache::EmoteCache, emote_types::DiscordEmote},
+};
+
+// Gateway opcodes - https://discord.com/developers/docs/topics/opcodes-and-status-codes
+const OP_DISPATCH: u8 = 0;
+const OP_HEARTBEAT: u8 = 1;
+const OP_IDENTIFY: u8 = 2;
+const OP_RECONNECT: u8 = 7;
+const OP_INVALID_SESSION: u8 = 9;
+const OP_HELLO: u8 = 10;
+
+const GUILD_MESSAGES_INTENT: u64 = 1 << 9;
+const MESSAGE_CONTENT_INTENT: u64 = 1 << 15;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Gateway close codes which connecting again will not fix, with why they were sent -
+/// https://discord.com/developers/docs/topics/opcodes-and-status-codes#gateway-gateway-close-event-codes
+const FATAL_CLOSE_CODES: &[(u16, &str)] = &[
+    (4004, "the bot token is invalid"),
+    (4010, "the shard is invalid"),
+    (4011, "the bot needs sharding"),
+    (4012, "the gateway version is invalid"),
+    (4013, "the intents are invalid"),
+    (
+        4014,
+        "the bot is not allowed the Message Content intent, enable it in the developer portal",
+    ),
+];
+
+type GatewayError = Box<dyn std::error::Error + Send + Sync>;
+type GatewayWriter = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+type GatewayReader = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+
+/// The gateway closed the connection for a reason which connecting again will not fix
+#[derive(Debug)]
+struct FatalClose {
+    code: u16,
+    reason: &'static str,
+}
+
+impl std::fmt::Display for FatalClose {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Discord gateway closed with code {}: {}",
+            self.code, self.reason
+        )
+    }
+}
+
+impl std::error::Error for FatalClose {}
+
+/// Chat source reading messages from a single Discord channel through the bot gateway
+pub(crate) struct DiscordSource {
+    config: DiscordConfig,
+    emote_cache: EmoteCache,
+    runtime: Handle,
+    task: Option<JoinHandle<()>>,
+}
+
+impl DiscordSource {
+    pub(crate) fn new(runtime: Handle, config: DiscordConfig, emote_cache: EmoteCache) -> Self {
+        Self {
+            config,
+            emote_cache,
+            runtime,
+            task: None,
+        }
+    }
+}
+
+impl ChatSource for DiscordSource {
+    fn start(&mut self, tx: mpsc::Sender<ChatEvent>) {
+        self.stop();
+        let config = self.config.clone();
+        let emote_cache = self.emote_cache.clone();
+        self.task = Some(self.runtime.spawn(async move {
+            start_discord_client(tx, config, emote_cache).await;
+        }));
+    }
+
+    fn stop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+/// Payload sent and received over the gateway websocket
+#[derive(Serialize, Deserialize, Debug)]
+struct GatewayPayload {
+    op: u8,
+    #[serde(default)]
+    d: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    s: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    t: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Hello {
+    heartbeat_interval: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessageCreate {
+    channel_id: String,
+    guild_id: Option<String>,
+    content: String,
+    author: Author,
+    member: Option<Member>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Author {
+    id: String,
+    username: String,
+    global_name: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Member {
+    nick: Option<String>,
+}
+
+impl From<MessageCreate> for ChatMessage {
+    fn from(msg: MessageCreate) -> Self {
+        let (message, emotes) = parse_discord_emotes(&msg.content);
+        // Prefer the server nickname, then the display name, then the username
+        let user = msg
+            .member
+            .and_then(|member| member.nick)
+            .or(msg.author.global_name)
+            .unwrap_or(msg.author.username);
+        Self {
+            platform: Platform::Discord,
+            // Deleted Discord messages are left to time out
+            id: None,
+            user_id: msg.author.id,
+            user,
+            // Role colors are not sent with messages
+            name_color: None,
+            badges: vec![],
+            message,
+            emotes,
+        }
+    }
+}
+
+async fn start_discord_client(
+    tx: mpsc::Sender<ChatEvent>,
+    config: DiscordConfig,
+    emote_cache: EmoteCache,
+) {
+    let mut meta_resolver = EmoteMetaResolver::new(tx.clone(), emote_cache);
+
+    loop {
+        match run_gateway(&tx, &config, &mut meta_resolver).await {
+            // Bevy has shut down
+            Ok(()) => break,
+            Err(err) if err.is::<FatalClose>() => {
+                error!("{}. Not reconnecting to Discord", err);
+                break;
+            }
+            Err(err) => warn!("Discord gateway disconnected: {}", err),
+        }
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Connect to the gateway and forward messages until the connection drops.
+/// Returns Ok once the receiving end of `tx` has been closed
+async fn run_gateway(
+    tx: &mpsc::Sender<ChatEvent>,
+    config: &DiscordConfig,
+    meta_resolver: &mut EmoteMetaResolver,
+) -> Result<(), GatewayError> {
+    info!("Connecting to the Discord gateway");
+    let (socket, _) = connect_async(&config.gateway_url).await?;
+    let (mut writer, mut reader) = socket.split();
+
+    // The gateway always starts with Hello containing the heartbeat interval
+    let hello = next_payload(&mut reader).await?;
+    if hello.op != OP_HELLO {
+        return Err(format!(
+            "Expected Hello from the Discord gateway, got op {}",
+            hello.op
+        )
+        .into());
+    }
+    let hello: Hello = serde_json::from_value(hello.d)?;
+
+    send_payload(
+        &mut writer,
+        OP_IDENTIFY,
+        json!({
+            "token": config.bot_token,
+            "intents": GUILD_MESSAGES_INTENT | MESSAGE_CONTENT_INTENT,
+            "properties": {
+                "os": std::env::consts::OS,
+                "browser": "minawan-watch-party",
+                "device": "minawan-watch-party",
+            },
+        }),
+    )
+    .await?;
+
+    let mut heartbeat = interval(Duration::from_millis(hello.heartbeat_interval));
+    // The first tick completes immediately
+    heartbeat.tick().await;
+    let mut sequence: Option<u64> = None;
+
+    loop {
+        tokio::select! {
+            _ = heartbeat.tick() => {
+                send_payload(&mut writer, OP_HEARTBEAT, json!(sequence)).await?;
+            }
+            payload = next_payload(&mut reader) => {
+                let payload = payload?;
+                if payload.s.is_some() {
+                    sequence = payload.s;
+                }
+                match payload.op {
+                    OP_DISPATCH if payload.t.as_deref() == Some("MESSAGE_CREATE") => {
+                        let msg: MessageCreate = match serde_json::from_value(payload.d) {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                warn!("Skipping unreadable Discord message: {}", err);
+                                continue;
+                            }
+                        };
+                        if msg.channel_id != config.channel_id
+                            || msg.guild_id.as_deref() != Some(config.guild_id.as_str())
+                        {
+                            continue;
+                        }
+                        let mut chat_message = ChatMessage::from(msg);
+                        info!("{}: {}", chat_message.user, chat_message.message);
+                        meta_resolver.resolve(&mut chat_message).await;
+                        if tx.send(ChatEvent::Message(chat_message)).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                    OP_HEARTBEAT => {
+                        send_payload(&mut writer, OP_HEARTBEAT, json!(sequence)).await?;
+                    }
+                    OP_RECONNECT | OP_INVALID_SESSION => {
+                        return Err("Discord gateway requested a reconnect".into());
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Read the next JSON payload, skipping any non-text frames
+async fn next_payload(reader: &mut GatewayReader) -> Result<GatewayPayload, GatewayError> {
+    while let Some(message) = reader.next().await {
+        match message? {
+            Message::Text(text) => return Ok(serde_json::from_str(&text)?),
+            Message::Close(frame) => {
+                let code = frame.as_ref().map(|frame| u16::from(frame.code));
+                if let Some(&(code, reason)) = FATAL_CLOSE_CODES
+                    .iter()
+                    .find(|(fatal_code, _)| Some(*fatal_code) == code)
+                {
+                    return Err(FatalClose { code, reason }.into());
+                }
+                return Err(format!("Discord gateway closed the connection: {:?}", frame).into());
+            }
+            _ => {}
+        }
+    }
+    Err("Discord gateway connection ended".into())
+}
+
+async fn send_payload(writer: &mut GatewayWriter, op: u8, d: Value) -> Result<(), GatewayError> {
+    let payload = GatewayPayload {
+        op,
+        d,
+        s: None,
+        t: None,
+    };
+    writer
+        .send(Message::Text(serde_json::to_string(&payload)?))
+        .await?;
+    Ok(())
+}
+
+/// Replace Discord custom emotes (`<:name:id>` and `<a:name:id>`) with their name so they are
+/// picked up as words by the message layout. Returns the new text and the emotes found in it
+fn parse_discord_emotes(content: &str) -> (String, Vec<EmoteSpan>) {
+    let mut message = String::new();
+    let mut emotes = vec![];
+    let mut rest = content;
+
+    while let Some(start) = rest.find('<') {
+        message.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match parse_emote_tag(rest) {
+            Some((emote, tag_length)) => {
+                // Emotes must be separated by whitespace to be displayed
+                if message.chars().last().is_some_and(|c| !c.is_whitespace()) {
+                    message.push(' ');
+                }
+                let char_start = message.chars().count();
+                message.push_str(&emote.name);
+                emotes.push(EmoteSpan {
+                    char_range: char_start..message.chars().count(),
+                    emote: emote.into(),
+                });
+                rest = &rest[tag_length..];
+                if rest.chars().next().is_some_and(|c| !c.is_whitespace()) {
+                    message.push(' ');
+                }
+            }
+            None => {
+                message.push('<');
+                rest = &rest[1..];
+            }
+        }
+    }
+    message.push_str(rest);
+
+    (message, emotes)
+}
+
+/// Parse an emote tag at the start of `text`, returning the emote and the length of the tag
+fn parse_emote_tag(text: &str) -> Option<(DiscordEmote, usize)> {
+    let end = text.find('>')?;
+    let inner = &text[1..end];
+    let (animated, inner) = match inner.strip_prefix("a:") {
+        Some(inner) => (true, inner),
+        None => (false, inner.strip_prefix(':')?),
+    };
+    let (name, id) = inner.split_once(':')?;
+    if name.is_empty()
+        || name.contains(char::is_whitespace)
+        || id.is_empty()
+        || !id.chars().all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+    Some((
+        DiscordEmote {
+            id: id.to_string(),
+            name: name.to_string(),
+            animated,
+        },
+        end + 1,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{net::TcpListener, time::timeout};
+    use tokio_tungstenite::{
+        accept_async,
+        tungstenite::protocol::{frame::coding::CloseCode, CloseFrame},
+    };
+
+    use super::*;
+
+    fn spans(emotes: &[EmoteSpan]) -> Vec<(&str, std::ops::Range<usize>, bool)> {
+        emotes
+            .iter()
+            .map(|span| {
+                (
+                    span.emote._id.as_str(),
+                    span.char_range.clone(),
+                    span.emote.animated,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_emote_tags() {
+        let (emote, length) = parse_emote_tag("<:a:1> hi").unwrap();
+        assert_eq!(
+            (emote.name.as_str(), emote.id.as_str(), emote.animated),
+            ("a", "1", false)
+        );
+        assert_eq!(length, 6);
+
+        let (emote, length) = parse_emote_tag("<a:b:2>").unwrap();
+        assert_eq!(
+            (emote.name.as_str(), emote.id.as_str(), emote.animated),
+            ("b", "2", true)
+        );
+        assert_eq!(length, 7);
+
+        for malformed in [
+            "<:a:>", "<::1>", "<:a:x1>", "<:a b:1>", "<a:1>", "<b:a:1>", "<:a:1", "<3",
+        ] {
+            assert!(parse_emote_tag(malformed).is_none(), "{}", malformed);
+        }
+    }
+
+    #[test]
+    fn replaces_emotes_with_their_names() {
+        let (message, emotes) = parse_discord_emotes("hello <:a:1> <a:b:2>");
+        assert_eq!(message, "hello a b");
+        assert_eq!(spans(&emotes), vec![("1", 6..7, false), ("2", 8..9, true)]);
+    }
+
+    #[test]
+    fn separates_emotes_from_adjacent_text() {
+        let (message, emotes) = parse_discord_emotes("hi<:wave:1>there<:a:1><a:b:2>");
+        assert_eq!(message, "hi wave there a b");
+        assert_eq!(
+            spans(&emotes),
+            vec![
+                ("1", 3..7, false),
+                ("1", 14..15, false),
+                ("2", 16..17, true)
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_malformed_tags_as_text() {
+        let (message, emotes) = parse_discord_emotes("<3 <:a:x> a<b <:c:");
+        assert_eq!(message, "<3 <:a:x> a<b <:c:");
+        assert!(emotes.is_empty());
+    }
+
+    #[test]
+    fn counts_multi_byte_characters() {
+        let (message, emotes) = parse_discord_emotes("ñé😀<:a:1> 😀 <a:bé:2>");
+        assert_eq!(message, "ñé😀 a 😀 bé");
+        assert_eq!(spans(&emotes), vec![("1", 4..5, false), ("2", 8..10, true)]);
+    }
+
+    fn message_create(guild_id: &str, channel_id: &str, content: &str) -> Message {
+        Message::Text(
+            json!({
+                "op": OP_DISPATCH,
+                "s": 1,
+                "t": "MESSAGE_CREATE",
+                "d": {
+                    "channel_id": channel_id,
+                    "guild_id": guild_id,
+                    "content": content,
+                    "author": { "id": "10", "username": "minawan", "global_name": "Minawan" },
+                    "member": { "nick": null },
+                },
+            })
+            .to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn forwards_messages_from_the_configured_channel() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // Stand-in gateway sending messages from other channels and servers before the one shown
+        let gateway = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = accept_async(stream).await.unwrap();
+            socket
+                .send(Message::Text(
+                    json!({ "op": OP_HELLO, "d": { "heartbeat_interval": 45000 } }).to_string(),
+                ))
+                .await
+                .unwrap();
+
+            let identify = socket.next().await.unwrap().unwrap();
+            let identify: GatewayPayload =
+                serde_json::from_str(identify.to_text().unwrap()).unwrap();
+            assert_eq!(identify.op, OP_IDENTIFY);
+            assert_eq!(identify.d["token"], "token");
+
+            for message in [
+                // Unreadable messages are skipped without dropping the connection
+                Message::Text(
+                    json!({ "op": OP_DISPATCH, "s": 1, "t": "MESSAGE_CREATE", "d": {} })
+                        .to_string(),
+                ),
+                message_create("1", "3", "other channel"),
+                message_create("3", "2", "other server"),
+                message_create("1", "2", "hello there"),
+            ] {
+                socket.send(message).await.unwrap();
+            }
+            // Keep the connection open until the test ends
+            while socket.next().await.is_some() {}
+        });
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut source = DiscordSource::new(
+            Handle::current(),
+            DiscordConfig {
+                bot_token: "token".to_string(),
+                guild_id: "1".to_string(),
+                channel_id: "2".to_string(),
+                gateway_url: format!("ws://{}", address),
+            },
+            EmoteCache::new("discord-test-cache", Handle::current()),
+        );
+        source.start(tx);
+
+        let event = timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("Message forwarded")
+            .unwrap();
+        let ChatEvent::Message(message) = event else {
+            panic!("Expected a chat message");
+        };
+        assert_eq!(message.platform, Platform::Discord);
+        assert_eq!(message.user_id, "10");
+        assert_eq!(message.user, "Minawan");
+        assert_eq!(message.message, "hello there");
+        assert!(rx.try_recv().is_err());
+
+        source.stop();
+        gateway.abort();
+    }
+
+    #[tokio::test]
+    async fn stops_after_close_codes_which_reconnecting_will_not_fix() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // Stand-in gateway rejecting the bot token
+        let gateway = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = accept_async(stream).await.unwrap();
+            socket
+                .send(Message::Text(
+                    json!({ "op": OP_HELLO, "d": { "heartbeat_interval": 45000 } }).to_string(),
+                ))
+                .await
+                .unwrap();
+            socket.next().await.unwrap().unwrap();
+            socket
+                .close(Some(CloseFrame {
+                    code: CloseCode::from(4004),
+                    reason: "Authentication failed.".into(),
+                }))
+                .await
+                .unwrap();
+            while socket.next().await.is_some() {}
+        });
+
+        let (tx, _rx) = mpsc::channel(10);
+        let client = tokio::spawn(start_discord_client(
+            tx,
+            DiscordConfig {
+                bot_token: "bad token".to_string(),
+                guild_id: "1".to_string(),
+                channel_id: "2".to_string(),
+                gateway_url: format!("ws://{}", address),
+            },
+            EmoteCache::new("discord-test-cache", Handle::current()),
+        ));
+        // The client gives up rather than waiting to reconnect
+        timeout(RECONNECT_DELAY / 2, client)
+            .await
+            .expect("Client stopped")
+            .unwrap();
+        gateway.abort();
+    }
+}
@@ -0,0 +1,301 @@
+pub mod discord;
+pub mod recorder;
+pub mod replay;
+pub mod seventv;
+pub mod twitch;
+
+use std::{collections::HashSet, ops::Range, sync::Arc, time::Duration};
+
+use bevy::{color::Color, prelude::Resource};
+use log::{info, warn};
+use tokio::{
+    runtime::Handle,
+    sync::{mpsc, Semaphore},
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+
+use crate::{
+    chat::{
+        discord::DiscordSource, replay::ReplaySource, seventv::SevenTVEventSource,
+        twitch::TwitchSource,
+    },
+    config::Config,
+    emotes::{
+        cache::EmoteCache, emote_types::Emote, get_channel_emotes, update_emote_meta,
+        ChannelEmotes, EmoteProvider, EmoteSetChange, EMOTE_META_TIMEOUT, EMOTE_META_WORKERS,
+    },
+};
+
+const EMOTE_RETRY_MIN_DELAY: Duration = Duration::from_secs(5);
+const EMOTE_RETRY_MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// Chat platform a message was received from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Platform {
+    Twitch,
+    Discord,
+}
+
+/// Platform independent chat message sent from a chat source to Bevy
+pub(crate) struct ChatMessage {
+    pub(crate) platform: Platform,
+    /// Used to remove the message if a moderator deletes it
+    pub(crate) id: Option<String>,
+    pub(crate) user_id: String,
+    /// Display name
+    pub(crate) user: String,
+    /// Color of the user's name in chat, if they have chosen one
+    pub(crate) name_color: Option<Color>,
+    pub(crate) badges: Vec<ChatBadge>,
+    pub(crate) message: String,
+    pub(crate) emotes: Vec<EmoteSpan>,
+}
+
+/// A chat badge such as moderator. The version picks the image, eg how long a subscriber has
+/// been subscribed or the amount of bits cheered
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct ChatBadge {
+    pub(crate) name: String,
+    pub(crate) version: String,
+}
+
+/// Event sent from a chat source to Bevy
+pub(crate) enum ChatEvent {
+    Message(ChatMessage),
+    /// The numeric id of the Twitch channel, known once the channel has been joined
+    ChannelId(String),
+    EmoteSetChange(EmoteSetChange),
+    /// The size and format of an emote sent earlier without them
+    EmoteMeta(Emote),
+    /// Channel emotes loaded in the background
+    ChannelEmotes(ChannelEmotes),
+    /// A moderator deleted the message with this id
+    MessageDeleted(String),
+    /// A user was banned or timed out, so their avatar and messages are removed
+    UserRemoved {
+        platform: Platform,
+        user_id: String,
+    },
+    /// A moderator cleared every message in the chat
+    ChatCleared,
+}
+
+/// An emote and the characters of the message it replaces. Only the word covering exactly these
+/// characters is drawn as the emote
+pub(crate) struct EmoteSpan {
+    pub(crate) emote: Emote,
+    pub(crate) char_range: Range<usize>,
+}
+
+/// A producer of chat messages, eg a Twitch channel
+pub(crate) trait ChatSource: Send + Sync {
+    /// Start sending messages into `tx`. Restarts the source if it is already running
+    fn start(&mut self, tx: mpsc::Sender<ChatEvent>);
+
+    /// Stop sending messages
+    fn stop(&mut self);
+}
+
+/// Every running chat source feeding the ChatReceiver
+#[derive(Resource)]
+pub(crate) struct ChatSources {
+    sources: Vec<Box<dyn ChatSource>>,
+    /// Kept separately so the emote set is still watched when chat reconnects
+    emote_events: Option<SevenTVEventSource>,
+    /// Task loading the channel emotes, which keeps retrying while 7TV cannot be reached
+    emote_loader: Option<JoinHandle<()>>,
+    sender: mpsc::Sender<ChatEvent>,
+    runtime: Handle,
+    emote_cache: EmoteCache,
+}
+
+impl ChatSources {
+    pub(crate) fn new(
+        sender: mpsc::Sender<ChatEvent>,
+        runtime: Handle,
+        emote_cache: EmoteCache,
+    ) -> Self {
+        Self {
+            sources: vec![],
+            emote_events: None,
+            emote_loader: None,
+            sender,
+            runtime,
+            emote_cache,
+        }
+    }
+
+    /// Stop any running sources and start the sources enabled in `config`
+    pub(crate) fn start(&mut self, config: &Config) {
+        self.stop();
+
+        if let Some(replay_config) = config.replay.clone() {
+            // Replays run offline in place of the live sources
+            self.sources.push(Box::new(ReplaySource::new(
+                self.runtime.clone(),
+                replay_config,
+                self.emote_cache.clone(),
+            )));
+        } else {
+            self.sources.push(Box::new(TwitchSource::new(
+                self.runtime.clone(),
+                config.channel_name.clone(),
+                config.recording.clone(),
+                self.emote_cache.clone(),
+            )));
+            if let Some(discord_config) = config.discord.clone() {
+                self.sources.push(Box::new(DiscordSource::new(
+                    self.runtime.clone(),
+                    discord_config,
+                    self.emote_cache.clone(),
+                )));
+            }
+        }
+
+        // Each source runs in a separate async task
+        for source in self.sources.iter_mut() {
+            source.start(self.sender.clone());
+        }
+    }
+
+    /// Stop and remove every source
+    pub(crate) fn stop(&mut self) {
+        for mut source in self.sources.drain(..) {
+            source.stop();
+        }
+    }
+
+    /// Watch a 7TV emote set for changes, replacing any set already being watched
+    pub(crate) fn watch_emote_set(&mut self, events_url: String, emote_set_id: String) {
+        self.stop_watching_emote_set();
+        let mut emote_events = SevenTVEventSource::new(
+            self.runtime.clone(),
+            events_url,
+            emote_set_id,
+            self.emote_cache.clone(),
+        );
+        emote_events.start(self.sender.clone());
+        self.emote_events = Some(emote_events);
+    }
+
+    pub(crate) fn stop_watching_emote_set(&mut self) {
+        if let Some(mut emote_events) = self.emote_events.take() {
+            emote_events.stop();
+        }
+    }
+
+    /// Load the emotes of every provider for a channel in the background, sending them as
+    /// `ChatEvent::ChannelEmotes`. Replaces any emotes already being loaded
+    pub(crate) fn load_channel_emotes(
+        &mut self,
+        channel_id: String,
+        providers: Vec<EmoteProvider>,
+    ) {
+        self.stop_loading_channel_emotes();
+        let tx = self.sender.clone();
+        let emote_cache = self.emote_cache.clone();
+        self.emote_loader = Some(self.runtime.spawn(async move {
+            let channel_emotes = get_channel_emotes(channel_id, &providers, &emote_cache).await;
+            // Bevy may have shut down, in which case the emotes are not needed
+            let _ = tx.send(ChatEvent::ChannelEmotes(channel_emotes)).await;
+        }));
+    }
+
+    /// Load the channel emotes again in the background until 7TV can be reached, waiting longer
+    /// after each failure. Replaces any emotes already being loaded
+    pub(crate) fn retry_channel_emotes(
+        &mut self,
+        channel_id: String,
+        providers: Vec<EmoteProvider>,
+    ) {
+        self.stop_loading_channel_emotes();
+        let tx = self.sender.clone();
+        let emote_cache = self.emote_cache.clone();
+        self.emote_loader = Some(self.runtime.spawn(async move {
+            let mut delay = EMOTE_RETRY_MIN_DELAY;
+            loop {
+                info!("Retrying the 7TV emotes in {} seconds", delay.as_secs());
+                sleep(delay).await;
+                let channel_emotes =
+                    get_channel_emotes(channel_id.clone(), &providers, &emote_cache).await;
+                if channel_emotes.seventv_error.is_none() {
+                    // Bevy may have shut down, in which case the emotes are not needed
+                    let _ = tx.send(ChatEvent::ChannelEmotes(channel_emotes)).await;
+                    return;
+                }
+                delay = (delay * 2).min(EMOTE_RETRY_MAX_DELAY);
+            }
+        }));
+    }
+
+    pub(crate) fn stop_loading_channel_emotes(&mut self) {
+        if let Some(emote_loader) = self.emote_loader.take() {
+            emote_loader.abort();
+        }
+    }
+}
+
+impl Drop for ChatSources {
+    fn drop(&mut self) {
+        self.stop();
+        self.stop_watching_emote_set();
+        self.stop_loading_channel_emotes();
+    }
+}
+
+/// Resolves the size and format of new emotes in the background so messages are sent straight
+/// away. Emotes are displayed as text until they are resolved and sent as `ChatEvent::EmoteMeta`
+pub(crate) struct EmoteMetaResolver {
+    tx: mpsc::Sender<ChatEvent>,
+    emote_cache: EmoteCache,
+    /// Limits the number of emote images being fetched at once
+    workers: Arc<Semaphore>,
+    seen_emotes: HashSet<String>,
+}
+
+impl EmoteMetaResolver {
+    pub(crate) fn new(tx: mpsc::Sender<ChatEvent>, emote_cache: EmoteCache) -> Self {
+        Self {
+            tx,
+            emote_cache,
+            workers: Arc::new(Semaphore::new(EMOTE_META_WORKERS)),
+            seen_emotes: HashSet::new(),
+        }
+    }
+
+    /// Fill in cached emotes in the message and start resolving any other emotes not seen before
+    pub(crate) async fn resolve(&mut self, chat_message: &mut ChatMessage) {
+        for span in chat_message.emotes.iter_mut() {
+            if !self.seen_emotes.insert(span.emote._id.clone()) {
+                continue;
+            }
+            if self.emote_cache.apply(&mut span.emote).await {
+                continue;
+            }
+
+            let mut emote = span.emote.clone();
+            let tx = self.tx.clone();
+            let emote_cache = self.emote_cache.clone();
+            let workers = self.workers.clone();
+            tokio::spawn(async move {
+                let Ok(_permit) = workers.acquire_owned().await else {
+                    return;
+                };
+                match timeout(
+                    EMOTE_META_TIMEOUT,
+                    update_emote_meta(&emote_cache, &mut emote),
+                )
+                .await
+                {
+                    Ok(Ok(())) => {
+                        // Bevy may have shut down, in which case the emote is not needed
+                        let _ = tx.send(ChatEvent::EmoteMeta(emote)).await;
+                    }
+                    Ok(Err(err)) => warn!("Cannot get the size of emote {}: {}", emote.name, err),
+                    Err(_) => warn!("Timed out getting the size of emote {}", emote.name),
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+
+use crate::{chat::replay::ReplayRecord, config::RecordingConfig};
+
+/// Appends received messages to rotating replay logs in the recording directory.
+/// Logs are named `<channel>-<unix millis>.jsonl`
+pub(crate) struct ChatRecorder {
+    config: RecordingConfig,
+    channel: String,
+    file: Option<File>,
+    file_size: u64,
+}
+
+impl ChatRecorder {
+    pub(crate) fn new(config: RecordingConfig, channel: String) -> Self {
+        Self {
+            config,
+            channel,
+            file: None,
+            file_size: 0,
+        }
+    }
+
+    /// Append a record to the current log, logging any failure rather than interrupting chat
+    pub(crate) fn record(&mut self, record: &ReplayRecord) {
+        if let Err(err) = self.try_record(record) {
+            warn!("Failed to record message: {}", err);
+            // Start a new file on the next message in case the current one was removed
+            self.file = None;
+        }
+    }
+
+    fn try_record(&mut self, record: &ReplayRecord) -> io::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+
+        if self.file.is_none() || self.file_size + line.len() as u64 > self.config.max_file_size {
+            self.rotate()?;
+        }
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(line.as_bytes())?;
+            self.file_size += line.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Open a new log file and remove the oldest logs over the file limit
+    fn rotate(&mut self) -> io::Result<()> {
+        fs::create_dir_all(&self.config.directory)?;
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path =
+            Path::new(&self.config.directory).join(format!("{}-{}.jsonl", self.channel, timestamp));
+        info!("Recording chat to {}", path.display());
+        self.file = Some(OpenOptions::new().create(true).append(true).open(&path)?);
+        self.file_size = 0;
+
+        let mut logs: Vec<PathBuf> = fs::read_dir(&self.config.directory)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "jsonl")
+                    && path
+                        .file_name()
+                        .and_then(|name| name.to_str())
+                        .is_some_and(|name| name.starts_with(&format!("{}-", self.channel)))
+            })
+            .collect();
+        // Timestamps all have the same number of digits so names sort oldest first
+        logs.sort();
+        let excess = logs.len().saturating_sub(self.config.max_files);
+        for old_log in logs.iter().take(excess) {
+            info!("Removing old recording {}", old_log.display());
+            fs::remove_file(old_log)?;
+        }
+        Ok(())
+    }
+}
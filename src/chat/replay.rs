@@ -0,0 +1,265 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use bevy::color::{Color, Srgba};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{runtime::Handle, sync::mpsc, task::JoinHandle, time::sleep};
+use twitch_irc::message::{IRCMessage, PrivmsgMessage};
+
+use crate::{
+    chat::{ChatBadge, ChatEvent, ChatMessage, ChatSource, EmoteMetaResolver, EmoteSpan, Platform},
+    config::ReplayConfig,
+    emotes::cache::EmoteCache,
+};
+
+const LOOP_DELAY: Duration = Duration::from_secs(1);
+
+/// One Twitch chat message in a replay log. Replay logs are JSON Lines files with one record
+/// per line. See the README for the format
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ReplayRecord {
+    /// Unix timestamp in milliseconds
+    pub(crate) timestamp: i64,
+    pub(crate) user_id: String,
+    pub(crate) user: String,
+    pub(crate) message: String,
+    #[serde(default)]
+    pub(crate) emotes: Vec<ReplayEmote>,
+    #[serde(default)]
+    pub(crate) tags: BTreeMap<String, String>,
+}
+
+/// A Twitch emote in a replay record covering the characters `start..end` of the message
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ReplayEmote {
+    pub(crate) id: String,
+    pub(crate) code: String,
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+}
+
+impl From<PrivmsgMessage> for ReplayRecord {
+    fn from(msg: PrivmsgMessage) -> Self {
+        Self {
+            timestamp: msg.server_timestamp.timestamp_millis(),
+            user_id: msg.sender.id,
+            user: msg.sender.name,
+            message: msg.message_text,
+            emotes: msg
+                .emotes
+                .into_iter()
+                .map(|emote| ReplayEmote {
+                    id: emote.id,
+                    code: emote.code,
+                    start: emote.char_range.start,
+                    end: emote.char_range.end,
+                })
+                .collect(),
+            tags: msg
+                .source
+                .tags
+                .0
+                .into_iter()
+                .map(|(key, value)| (key, value.unwrap_or_default()))
+                .collect(),
+        }
+    }
+}
+
+impl From<ReplayRecord> for ChatMessage {
+    fn from(record: ReplayRecord) -> Self {
+        Self {
+            platform: Platform::Twitch,
+            id: record.tags.get("id").filter(|id| !id.is_empty()).cloned(),
+            user_id: record.user_id,
+            user: record.user,
+            // The color tag is empty for users who have not chosen a color
+            name_color: record
+                .tags
+                .get("color")
+                .and_then(|color| Srgba::hex(color).ok())
+                .map(Color::from),
+            badges: record
+                .tags
+                .get("badges")
+                .map(|badges| parse_badges(badges))
+                .unwrap_or_default(),
+            message: record.message,
+            emotes: record
+                .emotes
+                .into_iter()
+                .map(|emote| EmoteSpan {
+                    char_range: emote.start..emote.end,
+                    emote: twitch_irc::message::Emote {
+                        id: emote.id,
+                        char_range: emote.start..emote.end,
+                        code: emote.code,
+                    }
+                    .into(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Parse the badges tag of a Twitch message, eg `moderator/1,subscriber/12`
+fn parse_badges(badges: &str) -> Vec<ChatBadge> {
+    badges
+        .split(',')
+        .filter_map(|badge| badge.split_once('/'))
+        .map(|(name, version)| ChatBadge {
+            name: name.to_string(),
+            version: version.to_string(),
+        })
+        .collect()
+}
+
+/// Parse a line of a replay log. Lines are either a JSON replay record or a raw
+/// Twitch IRC PRIVMSG, eg `@tmi-sent-ts=... :user!user@user.tmi.twitch.tv PRIVMSG #channel :hi`
+fn parse_replay_line(line: &str) -> Result<Option<ReplayRecord>, String> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.starts_with('{') {
+        return serde_json::from_str(line)
+            .map(Some)
+            .map_err(|err| err.to_string());
+    }
+    let irc_message = IRCMessage::parse(line).map_err(|err| err.to_string())?;
+    if irc_message.command != "PRIVMSG" {
+        return Ok(None);
+    }
+    let msg = PrivmsgMessage::try_from(irc_message).map_err(|err| err.to_string())?;
+    Ok(Some(msg.into()))
+}
+
+/// Chat source playing back a recorded replay log
+pub(crate) struct ReplaySource {
+    config: ReplayConfig,
+    emote_cache: EmoteCache,
+    runtime: Handle,
+    task: Option<JoinHandle<()>>,
+}
+
+impl ReplaySource {
+    pub(crate) fn new(runtime: Handle, config: ReplayConfig, emote_cache: EmoteCache) -> Self {
+        Self {
+            config,
+            emote_cache,
+            runtime,
+            task: None,
+        }
+    }
+}
+
+impl ChatSource for ReplaySource {
+    fn start(&mut self, tx: mpsc::Sender<ChatEvent>) {
+        self.stop();
+        let config = self.config.clone();
+        let emote_cache = self.emote_cache.clone();
+        self.task = Some(self.runtime.spawn(async move {
+            start_replay(tx, config, emote_cache).await;
+        }));
+    }
+
+    fn stop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+async fn start_replay(tx: mpsc::Sender<ChatEvent>, config: ReplayConfig, emote_cache: EmoteCache) {
+    let contents = match tokio::fs::read_to_string(&config.file).await {
+        Ok(contents) => contents,
+        Err(err) => {
+            warn!("Cannot read replay file {}: {}", config.file, err);
+            return;
+        }
+    };
+
+    let mut records: Vec<ReplayRecord> = vec![];
+    for (line_number, line) in contents.lines().enumerate() {
+        match parse_replay_line(line) {
+            Ok(Some(record)) => records.push(record),
+            Ok(None) => {}
+            Err(err) => warn!(
+                "Skipping line {} of {}: {}",
+                line_number + 1,
+                config.file,
+                err
+            ),
+        }
+    }
+    info!("Replaying {} messages from {}", records.len(), config.file);
+    if records.is_empty() {
+        return;
+    }
+
+    // Recorded Twitch messages carry the channel id, so the channel emotes can be loaded
+    if let Some(channel_id) = records
+        .iter()
+        .find_map(|record| record.tags.get("room-id").filter(|id| !id.is_empty()))
+    {
+        if tx
+            .send(ChatEvent::ChannelId(channel_id.clone()))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+
+    let mut meta_resolver = EmoteMetaResolver::new(tx.clone(), emote_cache);
+
+    loop {
+        let mut last_timestamp = records[0].timestamp;
+        for record in records.iter() {
+            // Wait for the gap between the original messages, scaled by the replay speed
+            let gap = record.timestamp.saturating_sub(last_timestamp);
+            sleep(replay_delay(gap, config.speed)).await;
+            last_timestamp = record.timestamp;
+
+            let mut chat_message = ChatMessage::from(record.clone());
+            info!("{}: {}", chat_message.user, chat_message.message);
+            meta_resolver.resolve(&mut chat_message).await;
+            if tx.send(ChatEvent::Message(chat_message)).await.is_err() {
+                // Bevy has shut down
+                return;
+            }
+        }
+
+        if !config.looping {
+            info!("Replay of {} finished", config.file);
+            return;
+        }
+        sleep(LOOP_DELAY).await;
+    }
+}
+
+/// Time to wait between two recorded messages `gap` milliseconds apart, scaled by the replay
+/// speed. Gaps too long to wait for are waited for as long as possible
+fn replay_delay(gap: i64, speed: f32) -> Duration {
+    let seconds = gap.max(0) as f64 / 1000.0 / speed as f64;
+    Duration::try_from_secs_f64(seconds).unwrap_or(Duration::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_gaps_by_the_replay_speed() {
+        assert_eq!(replay_delay(1500, 1.0), Duration::from_millis(1500));
+        assert_eq!(replay_delay(1500, 2.0), Duration::from_millis(750));
+        assert_eq!(replay_delay(-20, 1.0), Duration::ZERO);
+        assert_eq!(replay_delay(1500, f32::INFINITY), Duration::ZERO);
+    }
+
+    #[test]
+    fn waits_as_long_as_possible_for_gaps_too_long_to_wait_for() {
+        assert_eq!(replay_delay(1500, 1e-30), Duration::MAX);
+        assert_eq!(replay_delay(i64::MAX, f32::MIN_POSITIVE), Duration::MAX);
+    }
+}
@@ -0,0 +1,501 @@
+use std::time::Duration;
+
+use futures_util::{stream::SplitStream, SinkExt, StreamExt};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    net::TcpStream,
+    runtime::Handle,
+    sync::mpsc,
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::{
+    chat::ChatEvent,
+    emotes::{
+        cache::EmoteCache,
+        emote_types::{SevenTVEmoteBundle, SevenTVEmoteRef},
+        EmoteSetChange,
+    },
+};
+
+// EventAPI opcodes - https://github.com/SevenTV/EventAPI
+const OP_DISPATCH: u8 = 0;
+const OP_HELLO: u8 = 1;
+const OP_RECONNECT: u8 = 4;
+const OP_ERROR: u8 = 6;
+const OP_END_OF_STREAM: u8 = 7;
+const OP_SUBSCRIBE: u8 = 35;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+type EventError = Box<dyn std::error::Error + Send + Sync>;
+type EventReader = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+
+/// Source of changes to a 7TV emote set from the 7TV EventAPI
+pub(crate) struct SevenTVEventSource {
+    url: String,
+    emote_set_id: String,
+    emote_cache: EmoteCache,
+    runtime: Handle,
+    task: Option<JoinHandle<()>>,
+}
+
+impl SevenTVEventSource {
+    pub(crate) fn new(
+        runtime: Handle,
+        url: String,
+        emote_set_id: String,
+        emote_cache: EmoteCache,
+    ) -> Self {
+        Self {
+            url,
+            emote_set_id,
+            emote_cache,
+            runtime,
+            task: None,
+        }
+    }
+
+    /// Start forwarding changes to the emote set as `ChatEvent::EmoteSetChange`
+    pub(crate) fn start(&mut self, tx: mpsc::Sender<ChatEvent>) {
+        self.stop();
+        let url = self.url.clone();
+        let emote_set_id = self.emote_set_id.clone();
+        let emote_cache = self.emote_cache.clone();
+        self.task = Some(self.runtime.spawn(async move {
+            start_event_client(tx, url, emote_set_id, emote_cache).await;
+        }));
+    }
+
+    pub(crate) fn stop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct EventPayload {
+    op: u8,
+    #[serde(default)]
+    d: Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct Hello {
+    heartbeat_interval: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct Dispatch {
+    #[serde(rename = "type")]
+    event_type: String,
+    body: ChangeMap,
+}
+
+/// The fields of an object which changed
+#[derive(Deserialize, Debug)]
+struct ChangeMap {
+    #[serde(default)]
+    pushed: Vec<ChangeField>,
+    #[serde(default)]
+    pulled: Vec<ChangeField>,
+    #[serde(default)]
+    updated: Vec<ChangeField>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChangeField {
+    key: String,
+    #[serde(default)]
+    old_value: Value,
+    #[serde(default)]
+    value: Value,
+}
+
+async fn start_event_client(
+    tx: mpsc::Sender<ChatEvent>,
+    url: String,
+    emote_set_id: String,
+    emote_cache: EmoteCache,
+) {
+    loop {
+        match run_events(&tx, &url, &emote_set_id, &emote_cache).await {
+            // Bevy has shut down
+            Ok(()) => break,
+            Err(err) => warn!("7TV EventAPI disconnected: {}", err),
+        }
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Subscribe to updates of the emote set and forward them until the connection drops.
+/// Returns Ok once the receiving end of `tx` has been closed
+async fn run_events(
+    tx: &mpsc::Sender<ChatEvent>,
+    url: &str,
+    emote_set_id: &str,
+    emote_cache: &EmoteCache,
+) -> Result<(), EventError> {
+    info!("Connecting to the 7TV EventAPI");
+    let (socket, _) = connect_async(url).await?;
+    let (mut writer, mut reader) = socket.split();
+
+    let hello = next_payload(&mut reader).await?;
+    if hello.op != OP_HELLO {
+        return Err(format!("Expected Hello from the 7TV EventAPI, got op {}", hello.op).into());
+    }
+    let hello: Hello = serde_json::from_value(hello.d)?;
+
+    let subscribe = EventPayload {
+        op: OP_SUBSCRIBE,
+        d: json!({
+            "type": "emote_set.update",
+            "condition": { "object_id": emote_set_id },
+        }),
+    };
+    writer
+        .send(Message::Text(serde_json::to_string(&subscribe)?))
+        .await?;
+    info!("Watching 7TV emote set {}", emote_set_id);
+
+    // The server sends heartbeats, so a silent connection has been lost
+    let heartbeat_timeout = Duration::from_millis(hello.heartbeat_interval * 3);
+
+    loop {
+        let payload = timeout(heartbeat_timeout, next_payload(&mut reader))
+            .await
+            .map_err(|_| "7TV EventAPI heartbeat timed out")??;
+        match payload.op {
+            OP_DISPATCH => {
+                let dispatch: Dispatch = serde_json::from_value(payload.d)?;
+                if dispatch.event_type != "emote_set.update" {
+                    continue;
+                }
+                for mut change in emote_set_changes(dispatch.body) {
+                    // Added emotes seen on previous runs are shown from the cache
+                    if let EmoteSetChange::Added(emote) = &mut change {
+                        emote_cache.apply(emote).await;
+                    }
+                    if tx.send(ChatEvent::EmoteSetChange(change)).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            OP_RECONNECT | OP_END_OF_STREAM => {
+                return Err("7TV EventAPI requested a reconnect".into());
+            }
+            OP_ERROR => warn!("7TV EventAPI error: {}", payload.d),
+            _ => {}
+        }
+    }
+}
+
+/// Convert the emote changes of an emote set update, skipping any that cannot be read
+fn emote_set_changes(body: ChangeMap) -> Vec<EmoteSetChange> {
+    let mut changes = vec![];
+    for field in body
+        .pushed
+        .into_iter()
+        .filter(|field| field.key == "emotes")
+    {
+        match serde_json::from_value::<SevenTVEmoteBundle>(field.value) {
+            Ok(bundle) => changes.push(EmoteSetChange::Added(bundle.into())),
+            Err(err) => warn!("Cannot read added 7TV emote: {}", err),
+        }
+    }
+    for field in body
+        .pulled
+        .into_iter()
+        .filter(|field| field.key == "emotes")
+    {
+        match serde_json::from_value::<SevenTVEmoteRef>(field.old_value) {
+            Ok(emote) => changes.push(EmoteSetChange::Removed {
+                id: emote.id,
+                name: emote.name,
+            }),
+            Err(err) => warn!("Cannot read removed 7TV emote: {}", err),
+        }
+    }
+    for field in body
+        .updated
+        .into_iter()
+        .filter(|field| field.key == "emotes")
+    {
+        let old_emote = serde_json::from_value::<SevenTVEmoteRef>(field.old_value);
+        let new_emote = serde_json::from_value::<SevenTVEmoteRef>(field.value);
+        match (old_emote, new_emote) {
+            (Ok(old_emote), Ok(new_emote)) if old_emote.name != new_emote.name => {
+                changes.push(EmoteSetChange::Renamed {
+                    id: new_emote.id,
+                    old_name: old_emote.name,
+                    new_name: new_emote.name,
+                })
+            }
+            (Ok(_), Ok(_)) => {}
+            (Err(err), _) | (_, Err(err)) => warn!("Cannot read renamed 7TV emote: {}", err),
+        }
+    }
+    changes
+}
+
+/// Read the next JSON payload, skipping any non-text frames
+async fn next_payload(reader: &mut EventReader) -> Result<EventPayload, EventError> {
+    while let Some(message) = reader.next().await {
+        match message? {
+            Message::Text(text) => return Ok(serde_json::from_str(&text)?),
+            Message::Close(frame) => {
+                return Err(format!("7TV EventAPI closed the connection: {:?}", frame).into())
+            }
+            _ => {}
+        }
+    }
+    Err("7TV EventAPI connection ended".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::asset::Handle as AssetHandle;
+    use tokio::{net::TcpListener, time::timeout};
+    use tokio_tungstenite::accept_async;
+
+    use super::*;
+    use crate::{
+        emotes::{apply_emote_set_change, cache::EmoteCache, emote_types::Emote},
+        EmoteStorage,
+    };
+
+    const EMOTE_PUSHED: &str = include_str!("../../tests/fixtures/seventv_emote_pushed.json");
+    const EMOTE_PULLED: &str = include_str!("../../tests/fixtures/seventv_emote_pulled.json");
+    const EMOTE_UPDATED: &str = include_str!("../../tests/fixtures/seventv_emote_updated.json");
+
+    fn recorded_changes(payload: &str) -> Vec<EmoteSetChange> {
+        let payload: EventPayload = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload.op, OP_DISPATCH);
+        let dispatch: Dispatch = serde_json::from_value(payload.d).unwrap();
+        assert_eq!(dispatch.event_type, "emote_set.update");
+        emote_set_changes(dispatch.body)
+    }
+
+    fn storage(emotes: &[(&str, &str)]) -> EmoteStorage {
+        let mut emote_store = EmoteStorage::for_tests("seventv");
+        for (id, name) in emotes {
+            let emote: Emote = serde_json::from_value::<SevenTVEmoteBundle>(json!({
+                "id": id,
+                "name": name,
+                "data": {
+                    "id": id,
+                    "name": name,
+                    "animated": false,
+                    "host": {
+                        "url": format!("//cdn.7tv.app/emote/{}", id),
+                        "files": [{
+                            "name": "4x.webp",
+                            "static_name": "4x_static.webp",
+                            "width": 128,
+                            "height": 128,
+                        }],
+                    },
+                },
+            }))
+            .unwrap()
+            .into();
+            let handles = emote.add_static(AssetHandle::default(), 0);
+            emote_store.loaded.insert(emote.emote_url.clone(), handles);
+            emote_store.all.insert(name.to_string(), emote);
+        }
+        emote_store
+    }
+
+    fn names(emote_store: &EmoteStorage) -> Vec<(&str, &str)> {
+        let mut names: Vec<(&str, &str)> = emote_store
+            .all
+            .iter()
+            .map(|(name, emote)| (name.as_str(), emote._id.as_str()))
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn loaded(emote_store: &EmoteStorage) -> Vec<&str> {
+        let mut urls: Vec<&str> = emote_store.loaded.keys().map(String::as_str).collect();
+        urls.sort();
+        urls
+    }
+
+    #[test]
+    fn reads_pushed_emotes() {
+        let changes = recorded_changes(EMOTE_PUSHED);
+        assert_eq!(changes.len(), 2);
+        let EmoteSetChange::Added(emote) = &changes[0] else {
+            panic!("Expected an added emote");
+        };
+        assert_eq!(emote._id, "60ae958e229664e8667aea38");
+        assert_eq!(emote.name, "catJAM");
+        assert!(emote.animated);
+        assert!(!emote.zero_width);
+        assert_eq!(
+            emote.emote_url,
+            "https://cdn.7tv.app/emote/60ae958e229664e8667aea38/4x.webp"
+        );
+        assert_eq!((emote.width, emote.height), (Some(128), Some(128)));
+        let EmoteSetChange::Added(emote) = &changes[1] else {
+            panic!("Expected an added emote");
+        };
+        assert_eq!(emote.name, "RainTime");
+        assert!(emote.zero_width);
+    }
+
+    #[test]
+    fn reads_pulled_emotes() {
+        let changes = recorded_changes(EMOTE_PULLED);
+        assert_eq!(changes.len(), 1);
+        let EmoteSetChange::Removed { id, name } = &changes[0] else {
+            panic!("Expected a removed emote");
+        };
+        assert_eq!(id, "603caea243b9e100141caf4f");
+        assert_eq!(name, "PepeLaugh");
+    }
+
+    #[test]
+    fn reads_renamed_emotes_and_skips_other_updates() {
+        let changes = recorded_changes(EMOTE_UPDATED);
+        // The second update only changes the flags of Clap
+        assert_eq!(changes.len(), 1);
+        let EmoteSetChange::Renamed {
+            id,
+            old_name,
+            new_name,
+        } = &changes[0]
+        else {
+            panic!("Expected a renamed emote");
+        };
+        assert_eq!(id, "6042089e77137b000de9e669");
+        assert_eq!(old_name, "peepoHey");
+        assert_eq!(new_name, "minawanHey");
+    }
+
+    #[tokio::test]
+    async fn applies_recorded_changes() {
+        let mut emote_store = storage(&[
+            ("0123456789abcdef01234567", "catJAM"),
+            ("603caea243b9e100141caf4f", "PepeLaugh"),
+            ("6042089e77137b000de9e669", "peepoHey"),
+        ]);
+        for payload in [EMOTE_PUSHED, EMOTE_PULLED, EMOTE_UPDATED] {
+            for change in recorded_changes(payload) {
+                apply_emote_set_change(&mut emote_store, change);
+            }
+        }
+        assert_eq!(
+            names(&emote_store),
+            vec![
+                ("RainTime", "60bcb44f7229037ee386d1ab"),
+                ("catJAM", "60ae958e229664e8667aea38"),
+                ("minawanHey", "6042089e77137b000de9e669"),
+            ]
+        );
+        // The replaced catJAM and removed PepeLaugh images are dropped, the renamed emote keeps
+        // its image
+        assert_eq!(
+            loaded(&emote_store),
+            vec!["https://cdn.7tv.app/emote/6042089e77137b000de9e669/4x.webp"]
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_emotes_from_other_providers_alone() {
+        let mut emote_store = storage(&[("bttv-id", "PepeLaugh"), ("bttv-id-2", "peepoHey")]);
+        for payload in [EMOTE_PULLED, EMOTE_UPDATED] {
+            for change in recorded_changes(payload) {
+                apply_emote_set_change(&mut emote_store, change);
+            }
+        }
+        assert_eq!(
+            names(&emote_store),
+            vec![("PepeLaugh", "bttv-id"), ("peepoHey", "bttv-id-2")]
+        );
+        assert_eq!(loaded(&emote_store).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn forwards_recorded_emote_set_updates() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // Stand-in EventAPI replaying the recorded updates once subscribed
+        let event_api = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = accept_async(stream).await.unwrap();
+            socket
+                .send(Message::Text(
+                    json!({ "op": OP_HELLO, "d": { "heartbeat_interval": 45000 } }).to_string(),
+                ))
+                .await
+                .unwrap();
+
+            let subscribe = socket.next().await.unwrap().unwrap();
+            let subscribe: EventPayload =
+                serde_json::from_str(subscribe.to_text().unwrap()).unwrap();
+            assert_eq!(subscribe.op, OP_SUBSCRIBE);
+            assert_eq!(subscribe.d["type"], "emote_set.update");
+            assert_eq!(
+                subscribe.d["condition"]["object_id"],
+                "66c4a8f1e4b0c2a7d9f3b812"
+            );
+
+            for payload in [EMOTE_PUSHED, EMOTE_PULLED, EMOTE_UPDATED] {
+                socket
+                    .send(Message::Text(payload.to_string()))
+                    .await
+                    .unwrap();
+            }
+            // Keep the connection open until the test ends
+            while socket.next().await.is_some() {}
+        });
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut source = SevenTVEventSource::new(
+            Handle::current(),
+            format!("ws://{}", address),
+            "66c4a8f1e4b0c2a7d9f3b812".to_string(),
+            EmoteCache::new("seventv-test-cache", Handle::current()),
+        );
+        source.start(tx);
+
+        let mut changes = vec![];
+        for _ in 0..4 {
+            let event = timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .expect("Change forwarded")
+                .unwrap();
+            let ChatEvent::EmoteSetChange(change) = event else {
+                panic!("Expected an emote set change");
+            };
+            changes.push(match change {
+                EmoteSetChange::Added(emote) => format!("added {}", emote.name),
+                EmoteSetChange::Removed { name, .. } => format!("removed {}", name),
+                EmoteSetChange::Renamed {
+                    old_name, new_name, ..
+                } => format!("renamed {} to {}", old_name, new_name),
+            });
+        }
+        assert_eq!(
+            changes,
+            vec![
+                "added catJAM",
+                "added RainTime",
+                "removed PepeLaugh",
+                "renamed peepoHey to minawanHey",
+            ]
+        );
+        assert!(rx.try_recv().is_err());
+
+        source.stop();
+        event_api.abort();
+    }
+}
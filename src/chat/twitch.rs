@@ -0,0 +1,181 @@
+use std::time::Duration;
+
+use bevy::color::Color;
+use log::{error, info};
+use tokio::{runtime::Handle, sync::mpsc, task::JoinHandle, time::sleep};
+use twitch_irc::{
+    login::StaticLoginCredentials,
+    message::{ClearChatAction, PrivmsgMessage, ServerMessage},
+    ClientConfig, SecureTCPTransport, TwitchIRCClient,
+};
+
+use crate::{
+    chat::{
+        recorder::ChatRecorder, replay::ReplayRecord, ChatBadge, ChatEvent, ChatMessage,
+        ChatSource, EmoteMetaResolver, EmoteSpan, Platform,
+    },
+    config::RecordingConfig,
+    emotes::cache::EmoteCache,
+};
+
+/// Chat source reading messages from a Twitch channel over anonymous IRC
+pub(crate) struct TwitchSource {
+    channel: String,
+    recording: Option<RecordingConfig>,
+    emote_cache: EmoteCache,
+    runtime: Handle,
+    task: Option<JoinHandle<()>>,
+}
+
+impl TwitchSource {
+    pub(crate) fn new(
+        runtime: Handle,
+        channel: String,
+        recording: Option<RecordingConfig>,
+        emote_cache: EmoteCache,
+    ) -> Self {
+        Self {
+            channel,
+            recording,
+            emote_cache,
+            runtime,
+            task: None,
+        }
+    }
+}
+
+impl ChatSource for TwitchSource {
+    fn start(&mut self, tx: mpsc::Sender<ChatEvent>) {
+        self.stop();
+        let channel = self.channel.clone();
+        let recording = self.recording.clone();
+        let emote_cache = self.emote_cache.clone();
+        self.task = Some(self.runtime.spawn(async move {
+            start_twitch_client(tx, channel, recording, emote_cache).await;
+        }));
+    }
+
+    fn stop(&mut self) {
+        if let Some(task) = self.task.take() {
+            // Dropping the client with the task closes the connection
+            task.abort();
+        }
+    }
+}
+
+impl From<PrivmsgMessage> for ChatMessage {
+    fn from(msg: PrivmsgMessage) -> Self {
+        Self {
+            platform: Platform::Twitch,
+            id: Some(msg.message_id),
+            user_id: msg.sender.id,
+            user: msg.sender.name,
+            name_color: msg
+                .name_color
+                .map(|color| Color::srgb_u8(color.r, color.g, color.b)),
+            badges: msg
+                .badges
+                .into_iter()
+                .map(|badge| ChatBadge {
+                    name: badge.name,
+                    version: badge.version,
+                })
+                .collect(),
+            message: msg.message_text,
+            emotes: msg
+                .emotes
+                .into_iter()
+                .map(|emote| EmoteSpan {
+                    char_range: emote.char_range.clone(),
+                    emote: emote.into(),
+                })
+                .collect(),
+        }
+    }
+}
+
+async fn start_twitch_client(
+    tx: mpsc::Sender<ChatEvent>,
+    channel: String,
+    recording: Option<RecordingConfig>,
+    emote_cache: EmoteCache,
+) {
+    let mut recorder = recording.map(|config| ChatRecorder::new(config, channel.clone()));
+
+    let config = ClientConfig::new_simple(StaticLoginCredentials::anonymous());
+
+    let (mut incoming_messages, client) =
+        TwitchIRCClient::<SecureTCPTransport, StaticLoginCredentials>::new(config);
+
+    // Channel names are checked when the config is loaded, so this is not expected to fail
+    if let Err(err) = client.join(channel.clone()) {
+        error!("Cannot join Twitch channel {}: {}", channel, err);
+        return;
+    }
+
+    sleep(Duration::from_millis(2000)).await;
+
+    let mut meta_resolver = EmoteMetaResolver::new(tx.clone(), emote_cache);
+    let mut channel_id: Option<String> = None;
+
+    // Listen to incoming Twitch messages and send them to Bevy via the channel
+    while let Some(message) = incoming_messages.recv().await {
+        let event = match message {
+            ServerMessage::Privmsg(msg) => {
+                info!("{}: {}", msg.sender.name, msg.message_text);
+                if let Some(recorder) = recorder.as_mut() {
+                    recorder.record(&ReplayRecord::from(msg.clone()));
+                }
+                let mut chat_message = ChatMessage::from(msg);
+                meta_resolver.resolve(&mut chat_message).await;
+                ChatEvent::Message(chat_message)
+            }
+            ServerMessage::ClearMsg(msg) => {
+                info!("Message from {} deleted", msg.sender_login);
+                ChatEvent::MessageDeleted(msg.message_id)
+            }
+            ServerMessage::ClearChat(msg) => match msg.action {
+                ClearChatAction::ChatCleared => {
+                    info!("Chat cleared");
+                    ChatEvent::ChatCleared
+                }
+                ClearChatAction::UserBanned {
+                    user_login,
+                    user_id,
+                } => {
+                    info!("{} was banned", user_login);
+                    ChatEvent::UserRemoved {
+                        platform: Platform::Twitch,
+                        user_id,
+                    }
+                }
+                ClearChatAction::UserTimedOut {
+                    user_login,
+                    user_id,
+                    timeout_length,
+                } => {
+                    info!(
+                        "{} was timed out for {} seconds",
+                        user_login,
+                        timeout_length.as_secs()
+                    );
+                    ChatEvent::UserRemoved {
+                        platform: Platform::Twitch,
+                        user_id,
+                    }
+                }
+            },
+            // ROOMSTATE is sent after joining and carries the channel id in its room-id tag
+            ServerMessage::RoomState(state) if channel_id.as_ref() != Some(&state.channel_id) => {
+                info!("Joined channel with id {}", state.channel_id);
+                channel_id = Some(state.channel_id.clone());
+                ChatEvent::ChannelId(state.channel_id)
+            }
+            _ => continue,
+        };
+        if tx.send(event).await.is_err() {
+            // Bevy has shut down
+            break;
+        }
+    }
+}
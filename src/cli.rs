@@ -0,0 +1,49 @@
+use clap::Parser;
+
+use crate::config::ConfigOverride;
+
+/// Watch the minawan walk around while you watch the stream
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub(crate) struct Args {
+    /// Path to the config file
+    #[arg(long, default_value = "config.ini")]
+    pub(crate) config: String,
+
+    /// Twitch channel to watch, overrides CHANNEL_NAME
+    #[arg(long)]
+    pub(crate) channel: Option<String>,
+
+    /// Scale of everything on screen, overrides SCALE
+    #[arg(long)]
+    pub(crate) scale: Option<f32>,
+
+    /// Play back a replay log instead of connecting to chat
+    #[arg(long)]
+    pub(crate) replay: Option<String>,
+
+    /// Run without opening a window
+    #[arg(long)]
+    pub(crate) headless: bool,
+}
+
+impl Args {
+    /// Config values set by the arguments. These take priority over the config file and
+    /// environment variables
+    pub(crate) fn overrides(&self) -> Vec<ConfigOverride> {
+        let mut overrides = vec![];
+        if let Some(channel) = &self.channel {
+            overrides.push(ConfigOverride::new("Channel", "CHANNEL_NAME", channel));
+        }
+        if let Some(scale) = self.scale {
+            overrides.push(ConfigOverride::new("General", "SCALE", &scale.to_string()));
+        }
+        if let Some(replay) = &self.replay {
+            overrides.push(ConfigOverride::new("Replay", "FILE", replay));
+        }
+        if self.headless {
+            overrides.push(ConfigOverride::new("General", "HEADLESS", "true"));
+        }
+        overrides
+    }
+}
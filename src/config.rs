@@ -1,13 +1,73 @@
-use std::time::Duration;
+use std::{fmt, str::FromStr, time::Duration};
 
 use bevy::prelude::Resource;
 use ini::Ini;
 
+use crate::emotes::{EmoteProvider, EmoteProviders};
+
+const DISCORD_GATEWAY_URL: &str = "wss://gateway.discord.gg/?v=10&encoding=json";
+const SEVENTV_EVENTS_URL: &str = "wss://events.7tv.io/v3";
+
+/// Every section and the keys it may contain
+const KNOWN_KEYS: &[(&str, &[&str])] = &[
+    ("Channel", &["CHANNEL_NAME", "CHANNEL_ID"]),
+    ("General", &["SCALE", "HEADLESS", "SEED"]),
+    (
+        "Avatars",
+        &[
+            "AVATAR_URL",
+            "RANDOM_AVATARS",
+            "ACTION_DURATION_MILIS",
+            "WAIT_DURATION_MILIS",
+            "AVATAR_MOVE_SPEED",
+            "USER_DESPAWN_TIME_SECS",
+            "EDGE_BUFFER",
+            "SHOW_NAMES",
+            "SHOW_BADGES",
+            "BADGE_URL",
+            "BADGE_DIRECTORY",
+        ],
+    ),
+    (
+        "Messages",
+        &[
+            "FONT_URL",
+            "FONT_SIZE",
+            "EMOTE_SIZE_MULTIPLIER",
+            "MESSAGE_BOX_VERTICAL_OFFSET",
+            "MESSAGE_BOX_WIDTH",
+            "MESSAGE_DESPAWN_TIME_MILIS",
+            "EMOJI_DIRECTORY",
+            "SHORTEN_URLS",
+            "SHOW_NAMES_IN_MESSAGES",
+        ],
+    ),
+    (
+        "Emotes",
+        &[
+            "PROVIDERS",
+            "SEVENTV_EVENTS_URL",
+            "CACHE_DIRECTORY",
+            "MAX_LOADED_EMOTES",
+            "MAX_IMAGE_MEMORY_MB",
+        ],
+    ),
+    (
+        "Discord",
+        &["BOT_TOKEN", "GUILD_ID", "CHANNEL_ID", "GATEWAY_URL"],
+    ),
+    ("Replay", &["FILE", "SPEED", "LOOP"]),
+    ("Recording", &["DIRECTORY", "MAX_FILE_SIZE_MB", "MAX_FILES"]),
+];
+
 #[derive(Clone, Resource)]
 pub(crate) struct Config {
     pub(crate) channel_name: String,
-    pub(crate) channel_id: String,
+    /// Resolved from the channel once joined if not set
+    pub(crate) channel_id: Option<String>,
     pub(crate) scale: f32,
+    pub(crate) headless: bool,
+    pub(crate) seed: Option<u64>,
     pub(crate) avatar_url: String,
     pub(crate) random_avatars: bool,
     pub(crate) action_duration: Duration,
@@ -15,12 +75,62 @@ pub(crate) struct Config {
     pub(crate) avatar_move_speed: f32,
     pub(crate) user_despawn_time: Duration,
     pub(crate) edge_buffer: f32,
+    /// Show each user's name under their avatar
+    pub(crate) show_names: bool,
+    /// Show chat badges next to names
+    pub(crate) show_badges: bool,
+    /// Badge image url with `{name}` and `{version}` placeholders, the badge directory is used if
+    /// not set
+    pub(crate) badge_url: Option<String>,
+    /// Relative to the assets folder
+    pub(crate) badge_directory: String,
     pub(crate) font_url: String,
     pub(crate) font_size: f32,
     pub(crate) emote_size_multiplier: f32,
     pub(crate) message_box_vertical_offset: f32,
     pub(crate) message_box_width: f32,
     pub(crate) message_despawn_time: Duration,
+    /// Relative to the assets folder
+    pub(crate) emoji_directory: String,
+    /// Show only the domain of links
+    pub(crate) shorten_urls: bool,
+    /// Start messages with the sender's name
+    pub(crate) show_names_in_messages: bool,
+    /// Emote providers to load, earlier providers take precedence
+    pub(crate) emote_providers: Vec<EmoteProvider>,
+    pub(crate) seventv_events_url: String,
+    /// Relative to the assets folder
+    pub(crate) emote_cache_directory: String,
+    /// Unused emote images are released beyond this many loaded emotes
+    pub(crate) max_loaded_emotes: usize,
+    /// Unused emote images are released when images use more bytes than this
+    pub(crate) max_image_memory: u64,
+    pub(crate) discord: Option<DiscordConfig>,
+    pub(crate) replay: Option<ReplayConfig>,
+    pub(crate) recording: Option<RecordingConfig>,
+}
+
+#[derive(Clone, PartialEq)]
+pub(crate) struct DiscordConfig {
+    pub(crate) bot_token: String,
+    pub(crate) guild_id: String,
+    pub(crate) channel_id: String,
+    pub(crate) gateway_url: String,
+}
+
+#[derive(Clone, PartialEq)]
+pub(crate) struct ReplayConfig {
+    pub(crate) file: String,
+    pub(crate) speed: f32,
+    pub(crate) looping: bool,
+}
+
+#[derive(Clone, PartialEq)]
+pub(crate) struct RecordingConfig {
+    pub(crate) directory: String,
+    /// Maximum size of a single log in bytes
+    pub(crate) max_file_size: u64,
+    pub(crate) max_files: usize,
 }
 
 impl Config {
@@ -41,131 +151,520 @@ impl Config {
     }
 }
 
-pub(crate) fn load_config(filename: &str) -> Config {
-    let conf = Ini::load_from_file(filename).expect("Failed to load config.ini");
+#[cfg(test)]
+impl Config {
+    /// The default config for channel `minawan`, built in memory for tests
+    pub(crate) fn for_tests() -> Self {
+        Self {
+            channel_name: "minawan".to_string(),
+            channel_id: None,
+            scale: 1.0,
+            headless: true,
+            seed: None,
+            avatar_url: "avatars/avatar.png".to_string(),
+            random_avatars: false,
+            action_duration: Duration::from_millis(800),
+            wait_duration: Duration::from_millis(2000),
+            avatar_move_speed: 100.0,
+            user_despawn_time: Duration::from_secs(1800),
+            edge_buffer: 20.0,
+            show_names: true,
+            show_badges: true,
+            badge_url: None,
+            badge_directory: "badges".to_string(),
+            font_url: "fonts/ComicMono.ttf".to_string(),
+            font_size: 20.0,
+            emote_size_multiplier: 1.7,
+            message_box_vertical_offset: 35.0,
+            message_box_width: 200.0,
+            message_despawn_time: Duration::from_millis(10000),
+            emoji_directory: "emoji".to_string(),
+            shorten_urls: false,
+            show_names_in_messages: false,
+            emote_providers: vec![
+                EmoteProvider::SevenTV,
+                EmoteProvider::BetterTTV,
+                EmoteProvider::FrankerFaceZ,
+            ],
+            seventv_events_url: SEVENTV_EVENTS_URL.to_string(),
+            emote_cache_directory: "emote_cache".to_string(),
+            max_loaded_emotes: 500,
+            max_image_memory: megabytes(1024),
+            discord: None,
+            replay: None,
+            recording: None,
+        }
+    }
+}
 
-    // Load [Channel] section
-    let channel_section = conf
-        .section(Some("Channel"))
-        .expect("Missing [Channel] section");
+/// A single problem found in the config file
+#[derive(Debug)]
+pub(crate) enum ConfigProblem {
+    Unreadable(String),
+    MissingSection(&'static str),
+    MissingKey {
+        section: &'static str,
+        key: &'static str,
+    },
+    InvalidValue {
+        section: &'static str,
+        key: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+    OutOfRange {
+        section: &'static str,
+        key: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+    UnknownSection(String),
+    UnknownKey {
+        section: String,
+        key: String,
+    },
+}
 
-    let channel_name = channel_section
-        .get("CHANNEL_NAME")
-        .expect("Missing CHANNEL_NAME")
-        .to_string();
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigProblem::Unreadable(err) => write!(f, "Cannot read the file: {}", err),
+            ConfigProblem::MissingSection(section) => write!(f, "Missing [{}] section", section),
+            ConfigProblem::MissingKey { section, key } => {
+                write!(f, "Missing {} in [{}]", key, section)
+            }
+            ConfigProblem::InvalidValue {
+                section,
+                key,
+                value,
+                expected,
+            } => write!(
+                f,
+                "Invalid {} in [{}]: \"{}\" should be {}",
+                key, section, value, expected
+            ),
+            ConfigProblem::OutOfRange {
+                section,
+                key,
+                value,
+                expected,
+            } => write!(
+                f,
+                "{} in [{}] is out of range: {} should be {}",
+                key, section, value, expected
+            ),
+            ConfigProblem::UnknownSection(section) => write!(f, "Unknown section [{}]", section),
+            ConfigProblem::UnknownKey { section, key } => {
+                write!(f, "Unknown key {} in [{}]", key, section)
+            }
+        }
+    }
+}
 
-    let channel_id = channel_section
-        .get("CHANNEL_ID")
-        .expect("Missing CHANNEL_ID")
-        .to_string();
+/// Every problem found while loading the config file
+#[derive(Debug)]
+pub struct ConfigError {
+    pub(crate) filename: String,
+    pub(crate) problems: Vec<ConfigProblem>,
+}
 
-    // Load [General] section
-    let general_section = conf
-        .section(Some("General"))
-        .expect("Missing [General] section");
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Found {} problem(s) in {}:",
+            self.problems.len(),
+            self.filename
+        )?;
+        for problem in self.problems.iter() {
+            write!(f, "\n- {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A type that can be read from the config file
+trait ConfigValue: FromStr {
+    /// Description of a valid value used in error messages
+    const EXPECTED: &'static str;
+}
+
+impl ConfigValue for String {
+    const EXPECTED: &'static str = "text";
+}
+
+impl ConfigValue for bool {
+    const EXPECTED: &'static str = "true or false";
+}
+
+impl ConfigValue for f32 {
+    const EXPECTED: &'static str = "a number";
+}
+
+impl ConfigValue for u64 {
+    const EXPECTED: &'static str = "a whole number";
+}
+
+impl ConfigValue for usize {
+    const EXPECTED: &'static str = "a whole number";
+}
+
+impl ConfigValue for EmoteProviders {
+    const EXPECTED: &'static str = "a comma separated list of 7tv, bttv and ffz";
+}
+
+/// Reads values from the config file, collecting every problem instead of stopping at the first
+struct ConfigReader<'a> {
+    conf: &'a Ini,
+    problems: Vec<ConfigProblem>,
+}
+
+impl<'a> ConfigReader<'a> {
+    fn has_section(&self, section: &str) -> bool {
+        self.conf.section(Some(section)).is_some()
+    }
+
+    fn parse<T: ConfigValue>(&mut self, section: &'static str, key: &'static str) -> Option<T> {
+        let value = self.conf.section(Some(section))?.get(key)?;
+        match value.trim().parse::<T>() {
+            Ok(value) => Some(value),
+            Err(_) => {
+                self.problems.push(ConfigProblem::InvalidValue {
+                    section,
+                    key,
+                    value: value.to_string(),
+                    expected: T::EXPECTED,
+                });
+                None
+            }
+        }
+    }
+
+    /// Read a key which must be present. A missing section is reported by the caller
+    fn required<T: ConfigValue>(&mut self, section: &'static str, key: &'static str) -> Option<T> {
+        let properties = self.conf.section(Some(section))?;
+        if !properties.contains_key(key) {
+            self.problems
+                .push(ConfigProblem::MissingKey { section, key });
+        }
+        self.parse(section, key)
+    }
+
+    /// Read a key, using `default` if it is missing or invalid
+    fn optional<T: ConfigValue>(
+        &mut self,
+        section: &'static str,
+        key: &'static str,
+        default: T,
+    ) -> T {
+        self.parse(section, key).unwrap_or(default)
+    }
+
+    /// Check a value is greater than zero, returning `default` if not
+    fn positive<T>(&mut self, section: &'static str, key: &'static str, value: T, default: T) -> T
+    where
+        T: PartialOrd + Default + fmt::Display,
+    {
+        if value > T::default() {
+            value
+        } else {
+            self.problems.push(ConfigProblem::OutOfRange {
+                section,
+                key,
+                value: value.to_string(),
+                expected: "greater than 0",
+            });
+            default
+        }
+    }
+
+    /// Check a value is zero or more, returning `default` if not
+    fn non_negative(
+        &mut self,
+        section: &'static str,
+        key: &'static str,
+        value: f32,
+        default: f32,
+    ) -> f32 {
+        if value >= 0.0 {
+            value
+        } else {
+            self.problems.push(ConfigProblem::OutOfRange {
+                section,
+                key,
+                value: value.to_string(),
+                expected: "0 or more",
+            });
+            default
+        }
+    }
+
+    /// Report any sections or keys that are not used
+    fn check_unknown_keys(&mut self) {
+        for (section, properties) in self.conf.iter() {
+            let section_name = section.unwrap_or("no section");
+            let known_keys = section.and_then(|section| {
+                KNOWN_KEYS
+                    .iter()
+                    .find(|(known_section, _)| *known_section == section)
+                    .map(|(_, keys)| *keys)
+            });
+            match known_keys {
+                Some(known_keys) => {
+                    for (key, _) in properties.iter() {
+                        if !known_keys.contains(&key) {
+                            self.problems.push(ConfigProblem::UnknownKey {
+                                section: section_name.to_string(),
+                                key: key.to_string(),
+                            });
+                        }
+                    }
+                }
+                // Keys before the first section end up in the general section
+                None if section.is_none() => {
+                    for (key, _) in properties.iter() {
+                        self.problems.push(ConfigProblem::UnknownKey {
+                            section: section_name.to_string(),
+                            key: key.to_string(),
+                        });
+                    }
+                }
+                None => self
+                    .problems
+                    .push(ConfigProblem::UnknownSection(section_name.to_string())),
+            }
+        }
+    }
+}
+
+/// A config value set outside of the config file, eg from the command line
+#[derive(Clone, Debug)]
+pub struct ConfigOverride {
+    pub(crate) section: &'static str,
+    pub(crate) key: &'static str,
+    pub(crate) value: String,
+}
+
+impl ConfigOverride {
+    pub(crate) fn new(section: &'static str, key: &'static str, value: &str) -> Self {
+        Self {
+            section,
+            key,
+            value: value.to_string(),
+        }
+    }
+}
+
+/// Bytes in `mb` megabytes. Sizes too large to count in bytes are treated as the largest size
+fn megabytes(mb: u64) -> u64 {
+    mb.saturating_mul(1024 * 1024)
+}
+
+/// Whether a name can be joined on Twitch: 1 to 25 lowercase letters, numbers and underscores
+fn is_twitch_login(name: &str) -> bool {
+    (1..=25).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// Name of the environment variable overriding a key, eg `MINAWAN_GENERAL_SCALE`
+fn env_var_name(section: &str, key: &str) -> String {
+    format!("MINAWAN_{}_{}", section.to_uppercase(), key)
+}
+
+/// Load the config file. Every key except CHANNEL_NAME has a default, so only
+/// the channel needs to be configured.
+/// `MINAWAN_<SECTION>_<KEY>` environment variables are applied over the file, then `overrides`
+pub(crate) fn load_config(
+    filename: &str,
+    overrides: &[ConfigOverride],
+) -> Result<Config, ConfigError> {
+    let mut conf = Ini::load_from_file(filename).map_err(|err| ConfigError {
+        filename: filename.to_string(),
+        problems: vec![ConfigProblem::Unreadable(err.to_string())],
+    })?;
 
-    let scale = general_section
-        .get("SCALE")
-        .expect("Missing SCALE")
-        .parse::<f32>()
-        .expect("Invalid SCALE");
+    for (section, keys) in KNOWN_KEYS.iter() {
+        for key in keys.iter() {
+            if let Ok(value) = std::env::var(env_var_name(section, key)) {
+                conf.with_section(Some(*section)).set(*key, value);
+            }
+        }
+    }
+    for config_override in overrides.iter() {
+        conf.with_section(Some(config_override.section))
+            .set(config_override.key, config_override.value.clone());
+    }
+
+    let mut reader = ConfigReader {
+        conf: &conf,
+        problems: vec![],
+    };
+    reader.check_unknown_keys();
+
+    // Load [Channel] section
+    if !reader.has_section("Channel") {
+        reader
+            .problems
+            .push(ConfigProblem::MissingSection("Channel"));
+    }
+    // Twitch logins are lowercase, so names are accepted as they are shown on Twitch
+    let channel_name = reader
+        .required::<String>("Channel", "CHANNEL_NAME")
+        .map(|channel_name| channel_name.trim().to_lowercase());
+    if let Some(channel_name) = channel_name.as_ref().filter(|name| !is_twitch_login(name)) {
+        reader.problems.push(ConfigProblem::InvalidValue {
+            section: "Channel",
+            key: "CHANNEL_NAME",
+            value: channel_name.clone(),
+            expected: "a Twitch channel name of up to 25 letters, numbers and underscores",
+        });
+    }
+    let channel_id = reader
+        .parse::<String>("Channel", "CHANNEL_ID")
+        .map(|channel_id| channel_id.trim().to_string())
+        .filter(|channel_id| !channel_id.is_empty());
+
+    // Load [General] section
+    let scale = reader.optional("General", "SCALE", 1.0);
+    let scale = reader.positive("General", "SCALE", scale, 1.0);
+    let headless = reader.optional("General", "HEADLESS", false);
+    let seed = reader.parse::<u64>("General", "SEED");
 
     // Load [Avatars] section
-    let avatars_section = conf
-        .section(Some("Avatars"))
-        .expect("Missing [Avatars] section");
-
-    let avatar_url = avatars_section
-        .get("AVATAR_URL")
-        .expect("Missing AVATAR_URL")
-        .to_string();
-
-    let random_avatars = avatars_section
-        .get("RANDOM_AVATARS")
-        .expect("Missing RANDOM_AVATARS")
-        .parse::<bool>()
-        .expect("Invalid RANDOM_AVATARS");
-
-    let action_duration = Duration::from_millis(
-        avatars_section
-            .get("ACTION_DURATION_MILIS")
-            .expect("Missing ACTION_DURATION_MILIS")
-            .parse::<u64>()
-            .expect("Invalid ACTION_DURATION_MILIS")
-    );
+    let avatar_url = reader.optional("Avatars", "AVATAR_URL", "avatars/avatar.png".to_string());
+    let random_avatars = reader.optional("Avatars", "RANDOM_AVATARS", false);
+    let action_duration =
+        Duration::from_millis(reader.optional("Avatars", "ACTION_DURATION_MILIS", 800));
+    let wait_duration =
+        Duration::from_millis(reader.optional("Avatars", "WAIT_DURATION_MILIS", 2000));
+    let avatar_move_speed = reader.optional("Avatars", "AVATAR_MOVE_SPEED", 100.0);
+    let avatar_move_speed =
+        reader.non_negative("Avatars", "AVATAR_MOVE_SPEED", avatar_move_speed, 100.0);
+    let user_despawn_time =
+        Duration::from_secs(reader.optional("Avatars", "USER_DESPAWN_TIME_SECS", 1800));
+    let edge_buffer = reader.optional("Avatars", "EDGE_BUFFER", 20.0);
+    let edge_buffer = reader.non_negative("Avatars", "EDGE_BUFFER", edge_buffer, 20.0);
+    let show_names = reader.optional("Avatars", "SHOW_NAMES", true);
+    let show_badges = reader.optional("Avatars", "SHOW_BADGES", true);
+    let badge_url = reader
+        .parse::<String>("Avatars", "BADGE_URL")
+        .map(|badge_url| badge_url.trim().to_string())
+        .filter(|badge_url| !badge_url.is_empty());
+    let badge_directory = reader.optional("Avatars", "BADGE_DIRECTORY", "badges".to_string());
 
-    let wait_duration = Duration::from_millis(
-        avatars_section
-            .get("WAIT_DURATION_MILIS")
-            .expect("Missing WAIT_DURATION_MILIS")
-            .parse::<u64>()
-            .expect("Invalid WAIT_DURATION_MILIS")
+    // Load [Messages] section
+    let font_url = reader.optional("Messages", "FONT_URL", "fonts/ComicMono.ttf".to_string());
+    let font_size = reader.optional("Messages", "FONT_SIZE", 20.0);
+    let font_size = reader.positive("Messages", "FONT_SIZE", font_size, 20.0);
+    let emote_size_multiplier = reader.optional("Messages", "EMOTE_SIZE_MULTIPLIER", 1.7);
+    let emote_size_multiplier = reader.positive(
+        "Messages",
+        "EMOTE_SIZE_MULTIPLIER",
+        emote_size_multiplier,
+        1.7,
     );
+    let message_box_vertical_offset =
+        reader.optional("Messages", "MESSAGE_BOX_VERTICAL_OFFSET", 35.0);
+    let message_box_width = reader.optional("Messages", "MESSAGE_BOX_WIDTH", 200.0);
+    let message_box_width =
+        reader.positive("Messages", "MESSAGE_BOX_WIDTH", message_box_width, 200.0);
+    let message_despawn_time =
+        Duration::from_millis(reader.optional("Messages", "MESSAGE_DESPAWN_TIME_MILIS", 10000));
+    let emoji_directory = reader.optional("Messages", "EMOJI_DIRECTORY", "emoji".to_string());
+    let shorten_urls = reader.optional("Messages", "SHORTEN_URLS", false);
+    let show_names_in_messages = reader.optional("Messages", "SHOW_NAMES_IN_MESSAGES", false);
 
-    let avatar_move_speed = avatars_section
-        .get("AVATAR_MOVE_SPEED")
-        .expect("Missing AVATAR_MOVE_SPEED")
-        .parse::<f32>()
-        .expect("Invalid AVATAR_MOVE_SPEED");
-
-    let user_despawn_time = Duration::from_secs(
-        avatars_section
-            .get("USER_DESPAWN_TIME_SECS")
-            .expect("Missing USER_DESPAWN_TIME_SECS")
-            .parse::<u64>()
-            .expect("Invalid USER_DESPAWN_TIME_SECS")
+    // Load [Emotes] section
+    let emote_providers = reader
+        .optional(
+            "Emotes",
+            "PROVIDERS",
+            EmoteProviders(vec![
+                EmoteProvider::SevenTV,
+                EmoteProvider::BetterTTV,
+                EmoteProvider::FrankerFaceZ,
+            ]),
+        )
+        .0;
+    let seventv_events_url = reader.optional(
+        "Emotes",
+        "SEVENTV_EVENTS_URL",
+        SEVENTV_EVENTS_URL.to_string(),
     );
+    let emote_cache_directory =
+        reader.optional("Emotes", "CACHE_DIRECTORY", "emote_cache".to_string());
+    let max_loaded_emotes = reader.optional::<usize>("Emotes", "MAX_LOADED_EMOTES", 500);
+    let max_loaded_emotes = reader.positive("Emotes", "MAX_LOADED_EMOTES", max_loaded_emotes, 500);
+    let max_image_memory_mb = reader.optional::<u64>("Emotes", "MAX_IMAGE_MEMORY_MB", 1024);
+    let max_image_memory_mb =
+        reader.positive("Emotes", "MAX_IMAGE_MEMORY_MB", max_image_memory_mb, 1024);
 
-    let edge_buffer = avatars_section
-        .get("EDGE_BUFFER")
-        .expect("Missing EDGE_BUFFER")
-        .parse::<f32>()
-        .expect("Invalid EDGE_BUFFER");
+    // Load optional [Discord] section
+    let discord = if reader.has_section("Discord") {
+        let bot_token = reader.required::<String>("Discord", "BOT_TOKEN");
+        let guild_id = reader.required::<String>("Discord", "GUILD_ID");
+        let channel_id = reader.required::<String>("Discord", "CHANNEL_ID");
+        let gateway_url =
+            reader.optional("Discord", "GATEWAY_URL", DISCORD_GATEWAY_URL.to_string());
+        Some(DiscordConfig {
+            bot_token: bot_token.unwrap_or_default(),
+            guild_id: guild_id.unwrap_or_default(),
+            channel_id: channel_id.unwrap_or_default(),
+            gateway_url,
+        })
+    } else {
+        None
+    };
 
-    // Load [Messages] section
-    let general_section = conf.section(Some("Messages")).expect("Missing [General] section");
-
-    let font_url = general_section
-        .get("FONT_URL")
-        .expect("Missing FONT_URL")
-        .to_string();
-
-    let font_size = general_section
-        .get("FONT_SIZE")
-        .expect("Missing FONT_SIZE")
-        .parse::<f32>()
-        .expect("Invalid FONT_SIZE");
-
-    let emote_size_multiplier = general_section
-        .get("EMOTE_SIZE_MULTIPLIER")
-        .expect("Missing EMOTE_SIZE_MULTIPLIER")
-        .parse::<f32>()
-        .expect("Invalid EMOTE_SIZE_MULTIPLIER");
-
-    let message_box_vertical_offset = general_section
-        .get("MESSAGE_BOX_VERTICAL_OFFSET")
-        .expect("Missing MESSAGE_BOX_VERTICAL_OFFSET")
-        .parse::<f32>()
-        .expect("Invalid MESSAGE_BOX_VERTICAL_OFFSET");
-
-    let message_box_width = general_section
-        .get("MESSAGE_BOX_WIDTH")
-        .expect("Missing MESSAGE_BOX_WIDTH")
-        .parse::<f32>()
-        .expect("Invalid MESSAGE_BOX_WIDTH");
-
-    let message_despawn_time = Duration::from_millis(
-        general_section
-            .get("MESSAGE_DESPAWN_TIME_MILIS")
-            .expect("Missing MESSAGE_DESPAWN_TIME_MILIS")
-            .parse::<u64>()
-            .expect("Invalid MESSAGE_DESPAWN_TIME_MILIS")
-    );
+    // Load optional [Replay] section
+    let replay = if reader.has_section("Replay") {
+        let file = reader.required::<String>("Replay", "FILE");
+        let speed = reader.optional("Replay", "SPEED", 1.0);
+        let speed = reader.positive("Replay", "SPEED", speed, 1.0);
+        let looping = reader.optional("Replay", "LOOP", false);
+        Some(ReplayConfig {
+            file: file.unwrap_or_default(),
+            speed,
+            looping,
+        })
+    } else {
+        None
+    };
+
+    // Load optional [Recording] section
+    let recording = if reader.has_section("Recording") {
+        let directory = reader.optional("Recording", "DIRECTORY", "recordings".to_string());
+        let max_file_size_mb = reader.optional::<u64>("Recording", "MAX_FILE_SIZE_MB", 10);
+        let max_file_size_mb =
+            reader.positive("Recording", "MAX_FILE_SIZE_MB", max_file_size_mb, 10);
+        let max_files = reader.optional::<usize>("Recording", "MAX_FILES", 10);
+        let max_files = reader.positive("Recording", "MAX_FILES", max_files, 10);
+        Some(RecordingConfig {
+            directory,
+            max_file_size: megabytes(max_file_size_mb),
+            max_files,
+        })
+    } else {
+        None
+    };
+
+    if !reader.problems.is_empty() {
+        return Err(ConfigError {
+            filename: filename.to_string(),
+            problems: reader.problems,
+        });
+    }
 
-    Config {
-        channel_name,
+    Ok(Config {
+        channel_name: channel_name.unwrap_or_default(),
         channel_id,
         scale,
+        headless,
+        seed,
         avatar_url,
         random_avatars,
         action_duration,
@@ -173,11 +672,49 @@ pub(crate) fn load_config(filename: &str) -> Config {
         avatar_move_speed,
         user_despawn_time,
         edge_buffer,
+        show_names,
+        show_badges,
+        badge_url,
+        badge_directory,
         font_url,
         font_size,
         emote_size_multiplier,
         message_box_vertical_offset,
         message_box_width,
         message_despawn_time,
+        emoji_directory,
+        shorten_urls,
+        show_names_in_messages,
+        emote_providers,
+        seventv_events_url,
+        emote_cache_directory,
+        max_loaded_emotes,
+        max_image_memory: megabytes(max_image_memory_mb),
+        discord,
+        replay,
+        recording,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_megabytes_without_overflowing() {
+        assert_eq!(megabytes(10), 10 * 1024 * 1024);
+        assert_eq!(megabytes(u64::MAX / 1024), u64::MAX);
+    }
+
+    #[test]
+    fn accepts_only_twitch_logins() {
+        assert!(is_twitch_login("minawan"));
+        assert!(is_twitch_login("mina_wan_2024"));
+        assert!(is_twitch_login(&"a".repeat(25)));
+        assert!(!is_twitch_login(""));
+        assert!(!is_twitch_login(&"a".repeat(26)));
+        assert!(!is_twitch_login("mina wan"));
+        assert!(!is_twitch_login("#minawan"));
+        assert!(!is_twitch_login("minawän"));
     }
 }
@@ -0,0 +1,214 @@
+use std::path::PathBuf;
+
+use bevy::{asset::io::file::FileAssetReader, render::texture::ImageFormat};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+
+use crate::emotes::emote_types::Emote;
+
+type CacheError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Emote images and their metadata saved to disk so they are not downloaded again on later runs.
+/// Entries are named by a hash of the emote url, which identifies the provider, emote and size
+#[derive(Clone)]
+pub(crate) struct EmoteCache {
+    /// Cache directory relative to the assets folder, used to load images with the asset server
+    asset_dir: String,
+    /// The cache directory on disk
+    dir: PathBuf,
+    runtime: Handle,
+}
+
+/// Metadata stored alongside each cached image as `<key>.json`
+#[derive(Serialize, Deserialize, Debug)]
+struct CachedEmoteMeta {
+    url: String,
+    width: u32,
+    height: u32,
+    /// File extension of the image, eg `webp`
+    extension: String,
+    animated: bool,
+}
+
+impl EmoteCache {
+    pub(crate) fn new(asset_dir: &str, runtime: Handle) -> Self {
+        Self {
+            asset_dir: asset_dir.trim_end_matches('/').to_string(),
+            dir: FileAssetReader::get_base_path()
+                .join("assets")
+                .join(asset_dir),
+            runtime,
+        }
+    }
+
+    /// Fill in the size, format and cached image of an emote. Returns false if it is not cached
+    pub(crate) async fn apply(&self, emote: &mut Emote) -> bool {
+        let key = cache_key(&emote.emote_url);
+        let meta = match tokio::fs::read_to_string(self.dir.join(format!("{}.json", key))).await {
+            Ok(meta) => meta,
+            Err(_) => return false,
+        };
+        let meta: CachedEmoteMeta = match serde_json::from_str(&meta) {
+            Ok(meta) => meta,
+            Err(err) => {
+                warn!(
+                    "Ignoring unreadable cache entry for {}: {}",
+                    emote.emote_url, err
+                );
+                return false;
+            }
+        };
+        let image_file = format!("{}.{}", key, meta.extension);
+        let Some(format) = ImageFormat::from_extension(&meta.extension) else {
+            return false;
+        };
+        if meta.url != emote.emote_url {
+            return false;
+        }
+        match tokio::fs::metadata(self.dir.join(&image_file)).await {
+            Ok(metadata) if metadata.is_file() => {}
+            _ => return false,
+        }
+
+        emote.width = Some(meta.width);
+        emote.height = Some(meta.height);
+        emote.format = Some(format);
+        emote.animated = meta.animated;
+        emote.cache_path = Some(format!("{}/{}", self.asset_dir, image_file));
+        true
+    }
+
+    /// Download the emote image into the cache and fill in its size and format
+    pub(crate) async fn store(&self, emote: &mut Emote) -> Result<(), CacheError> {
+        let bytes = reqwest::get(&emote.emote_url)
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        let reader = image::ImageReader::new(std::io::Cursor::new(&bytes)).with_guessed_format()?;
+        let image_format = reader.format().ok_or("Unknown image format")?;
+        let (width, height) = reader.into_dimensions()?;
+        let format = ImageFormat::from_image_crate_format(image_format)
+            .ok_or("Image format not supported by Bevy")?;
+        let (extension, format_animated) = match format {
+            ImageFormat::Png => ("png", false),
+            ImageFormat::Gif => ("gif", true),
+            ImageFormat::WebP => ("webp", true),
+            _ => return Err(format!("Cannot cache {:?} images", format).into()),
+        };
+        // Providers which give the format also say if the emote is animated, eg static 7TV webp
+        let animated = match emote.format {
+            Some(_) => emote.animated,
+            None => format_animated,
+        };
+
+        let key = cache_key(&emote.emote_url);
+        let image_file = format!("{}.{}", key, extension);
+        let meta = CachedEmoteMeta {
+            url: emote.emote_url.clone(),
+            width,
+            height,
+            extension: extension.to_string(),
+            animated,
+        };
+        tokio::fs::create_dir_all(&self.dir).await?;
+        tokio::fs::write(self.dir.join(&image_file), &bytes).await?;
+        // Written last so a partial entry is never used
+        tokio::fs::write(
+            self.dir.join(format!("{}.json", key)),
+            serde_json::to_string(&meta)?,
+        )
+        .await?;
+        debug!("Cached {} as {}", emote.emote_url, image_file);
+
+        emote.width = Some(width);
+        emote.height = Some(height);
+        emote.format = Some(format);
+        emote.animated = animated;
+        emote.cache_path = Some(format!("{}/{}", self.asset_dir, image_file));
+        Ok(())
+    }
+
+    /// Cache an emote without waiting, so it is loaded from disk on the next run
+    pub(crate) fn store_in_background(&self, mut emote: Emote) {
+        let cache = self.clone();
+        self.runtime.spawn(async move {
+            if let Err(err) = cache.store(&mut emote).await {
+                warn!("Cannot cache emote {}: {}", emote.name, err);
+            }
+        });
+    }
+}
+
+/// Stable hash of an emote url. Unlike `DefaultHasher`, FNV-1a is the same between builds
+fn cache_key(url: &str) -> String {
+    let hash = url.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emote(url: &str) -> Emote {
+        Emote {
+            _id: "1".to_string(),
+            name: "catJAM".to_string(),
+            animated: false,
+            emote_url: url.to_string(),
+            format: None,
+            width: None,
+            height: None,
+            zero_width: false,
+            cache_path: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_cached_entries() {
+        let dir = std::env::temp_dir().join(format!("minawan-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // An absolute directory is used as it is rather than inside the assets folder
+        let cache = EmoteCache::new(dir.to_str().unwrap(), Handle::current());
+
+        let url = "https://cdn.7tv.app/emote/1/4x.webp";
+        let key = cache_key(url);
+        let meta = CachedEmoteMeta {
+            url: url.to_string(),
+            width: 112,
+            height: 128,
+            extension: "webp".to_string(),
+            animated: true,
+        };
+        std::fs::write(
+            dir.join(format!("{}.json", key)),
+            serde_json::to_string(&meta).unwrap(),
+        )
+        .unwrap();
+
+        // The metadata alone is not used
+        let mut cached = emote(url);
+        assert!(!cache.apply(&mut cached).await);
+        assert!(cached.width.is_none());
+
+        std::fs::write(dir.join(format!("{}.webp", key)), b"image").unwrap();
+        assert!(cache.apply(&mut cached).await);
+        assert_eq!((cached.width, cached.height), (Some(112), Some(128)));
+        assert!(matches!(cached.format, Some(ImageFormat::WebP)));
+        assert!(cached.animated);
+        assert_eq!(
+            cached.cache_path,
+            Some(format!("{}/{}.webp", dir.to_str().unwrap(), key))
+        );
+
+        assert!(
+            !cache
+                .apply(&mut emote("https://cdn.7tv.app/emote/2/4x.webp"))
+                .await
+        );
+    }
+}
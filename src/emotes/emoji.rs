@@ -0,0 +1,278 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use bevy::{asset::io::file::FileAssetReader, render::texture::ImageFormat, utils::HashMap};
+use log::warn;
+
+use crate::emotes::emote_types::Emote;
+
+const ZERO_WIDTH_JOINER: char = '\u{200D}';
+const EMOJI_PRESENTATION: char = '\u{FE0F}';
+const KEYCAP: char = '\u{20E3}';
+
+/// Part of a word which has been split around emoji
+#[derive(Debug)]
+pub(crate) enum WordPiece<'a> {
+    Text(&'a str),
+    Emoji(Emote),
+}
+
+/// Emoji images in the assets folder, named by their code points like Twemoji eg `1f600.png`.
+/// Emoji without an image are shown as text
+pub(crate) struct EmojiImages {
+    /// Image directory relative to the assets folder, used to load images with the asset server
+    asset_dir: String,
+    /// The image directory on disk
+    dir: PathBuf,
+    /// Emoji looked up so far, None if there is no image for it
+    known: HashMap<String, Option<Emote>>,
+}
+
+impl EmojiImages {
+    pub(crate) fn new(asset_dir: &str) -> Self {
+        let dir = FileAssetReader::get_base_path()
+            .join("assets")
+            .join(asset_dir);
+        if !has_png(&dir) {
+            // The images are not part of the repository
+            warn!(
+                "No emoji images in {}, emoji are shown as text. Run scripts/fetch-emoji.sh to \
+                 download them",
+                dir.display()
+            );
+        }
+        Self {
+            asset_dir: asset_dir.trim_end_matches('/').to_string(),
+            dir,
+            known: HashMap::new(),
+        }
+    }
+
+    /// Split a word into text and the emoji which have an image. Returns None if there are none
+    pub(crate) fn split<'a>(&mut self, word: &'a str) -> Option<Vec<WordPiece<'a>>> {
+        let mut pieces = vec![];
+        let mut text_start = 0;
+        for (start, end) in emoji_clusters(word) {
+            let Some(emote) = self.get(&word[start..end]) else {
+                continue;
+            };
+            if text_start < start {
+                pieces.push(WordPiece::Text(&word[text_start..start]));
+            }
+            pieces.push(WordPiece::Emoji(emote));
+            text_start = end;
+        }
+        if pieces.is_empty() {
+            return None;
+        }
+        if text_start < word.len() {
+            pieces.push(WordPiece::Text(&word[text_start..]));
+        }
+        Some(pieces)
+    }
+
+    fn get(&mut self, emoji: &str) -> Option<Emote> {
+        if let Some(emote) = self.known.get(emoji) {
+            return emote.clone();
+        }
+        let file = format!("{}.png", emoji_file_name(emoji));
+        // Reading the header also checks the image exists
+        let emote = image::image_dimensions(self.dir.join(&file))
+            .ok()
+            .map(|(width, height)| {
+                let path = format!("{}/{}", self.asset_dir, file);
+                Emote {
+                    _id: file,
+                    name: emoji.to_string(),
+                    animated: false,
+                    emote_url: path.clone(),
+                    format: Some(ImageFormat::Png),
+                    width: Some(width),
+                    height: Some(height),
+                    zero_width: false,
+                    cache_path: Some(path),
+                }
+            });
+        self.known.insert(emoji.to_string(), emote.clone());
+        emote
+    }
+}
+
+fn has_png(dir: &Path) -> bool {
+    fs::read_dir(dir).is_ok_and(|entries| {
+        entries
+            .flatten()
+            .any(|entry| entry.path().extension().is_some_and(|ext| ext == "png"))
+    })
+}
+
+/// Twemoji file name of an emoji, its code points in hex joined by `-`. The emoji presentation
+/// selector is left out unless the emoji is a sequence joined with zero width joiners
+fn emoji_file_name(emoji: &str) -> String {
+    let joined = emoji.contains(ZERO_WIDTH_JOINER);
+    emoji
+        .chars()
+        .filter(|c| joined || *c != EMOJI_PRESENTATION)
+        .map(|c| format!("{:x}", c as u32))
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Byte ranges of the emoji in a word, including skin tones and sequences joined by zero width
+/// joiners such as family emoji
+fn emoji_clusters(word: &str) -> Vec<(usize, usize)> {
+    let chars: Vec<(usize, char)> = word.char_indices().collect();
+    let mut clusters = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let (start, c) = chars[i];
+        let mut end = i + 1;
+        if is_regional_indicator(c) {
+            // Flags are pairs of regional indicators
+            if chars
+                .get(end)
+                .is_some_and(|(_, next)| is_regional_indicator(*next))
+            {
+                end += 1;
+            }
+        } else if c.is_ascii_digit() || c == '#' || c == '*' {
+            // Keycaps such as 1️⃣
+            if chars
+                .get(end)
+                .is_some_and(|(_, next)| *next == EMOJI_PRESENTATION)
+            {
+                end += 1;
+            }
+            if chars.get(end).is_some_and(|(_, next)| *next == KEYCAP) {
+                end += 1;
+            } else {
+                i += 1;
+                continue;
+            }
+        } else if is_pictographic(c)
+            && (has_emoji_presentation(c)
+                || chars
+                    .get(end)
+                    .is_some_and(|(_, next)| *next == EMOJI_PRESENTATION))
+        {
+            // Symbols such as © are text unless followed by the emoji presentation selector
+            loop {
+                while chars
+                    .get(end)
+                    .is_some_and(|(_, next)| is_emoji_modifier(*next))
+                {
+                    end += 1;
+                }
+                if chars
+                    .get(end)
+                    .is_some_and(|(_, next)| *next == ZERO_WIDTH_JOINER)
+                    && chars
+                        .get(end + 1)
+                        .is_some_and(|(_, next)| is_pictographic(*next))
+                {
+                    end += 2;
+                } else {
+                    break;
+                }
+            }
+        } else {
+            i += 1;
+            continue;
+        }
+        let end_byte = chars.get(end).map_or(word.len(), |(index, _)| *index);
+        clusters.push((start, end_byte));
+        i = end;
+    }
+    clusters
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+/// Characters following an emoji which change how it is drawn, eg skin tones
+fn is_emoji_modifier(c: char) -> bool {
+    c == EMOJI_PRESENTATION
+        || c == KEYCAP
+        || ('\u{1F3FB}'..='\u{1F3FF}').contains(&c)
+        // Tags, used by subdivision flags
+        || ('\u{E0020}'..='\u{E007F}').contains(&c)
+}
+
+/// Approximation of the Unicode Extended_Pictographic property
+fn is_pictographic(c: char) -> bool {
+    matches!(c,
+        '\u{A9}' | '\u{AE}' | '\u{203C}' | '\u{2049}' | '\u{2122}' | '\u{2139}'
+        | '\u{2194}'..='\u{2199}'
+        | '\u{21A9}'..='\u{21AA}'
+        | '\u{231A}'..='\u{23FF}'
+        | '\u{24C2}'
+        | '\u{25AA}'..='\u{25FE}'
+        | '\u{2600}'..='\u{27BF}'
+        | '\u{2934}'..='\u{2935}'
+        | '\u{2B05}'..='\u{2B55}'
+        | '\u{3030}' | '\u{303D}' | '\u{3297}' | '\u{3299}'
+        | '\u{1F000}'..='\u{1FAFF}'
+    ) && !is_regional_indicator(c)
+        && !is_emoji_modifier(c)
+}
+
+/// Approximation of the Unicode Emoji_Presentation property, pictographs drawn as emoji even
+/// without the emoji presentation selector
+fn has_emoji_presentation(c: char) -> bool {
+    matches!(c,
+        '\u{231A}'..='\u{231B}' | '\u{23E9}'..='\u{23EC}' | '\u{23F0}' | '\u{23F3}'
+        | '\u{25FD}'..='\u{25FE}' | '\u{2614}'..='\u{2615}' | '\u{2648}'..='\u{2653}'
+        | '\u{267F}' | '\u{2693}' | '\u{26A1}' | '\u{26AA}'..='\u{26AB}'
+        | '\u{26BD}'..='\u{26BE}' | '\u{26C4}'..='\u{26C5}' | '\u{26CE}' | '\u{26D4}'
+        | '\u{26EA}' | '\u{26F2}'..='\u{26F3}' | '\u{26F5}' | '\u{26FA}' | '\u{26FD}'
+        | '\u{2705}' | '\u{270A}'..='\u{270B}' | '\u{2728}' | '\u{274C}' | '\u{274E}'
+        | '\u{2753}'..='\u{2755}' | '\u{2757}' | '\u{2795}'..='\u{2797}' | '\u{27B0}'
+        | '\u{27BF}' | '\u{2B1B}'..='\u{2B1C}' | '\u{2B50}' | '\u{2B55}'
+        | '\u{1F004}' | '\u{1F0CF}' | '\u{1F18E}' | '\u{1F191}'..='\u{1F19A}'
+        | '\u{1F201}' | '\u{1F21A}' | '\u{1F22F}' | '\u{1F232}'..='\u{1F236}'
+        | '\u{1F238}'..='\u{1F23A}' | '\u{1F250}'..='\u{1F251}'
+        | '\u{1F300}'..='\u{1F320}' | '\u{1F32D}'..='\u{1F335}' | '\u{1F337}'..='\u{1F37C}'
+        | '\u{1F37E}'..='\u{1F393}' | '\u{1F3A0}'..='\u{1F3CA}' | '\u{1F3CF}'..='\u{1F3D3}'
+        | '\u{1F3E0}'..='\u{1F3F0}' | '\u{1F3F4}' | '\u{1F3F8}'..='\u{1F43E}' | '\u{1F440}'
+        | '\u{1F442}'..='\u{1F4FC}' | '\u{1F4FF}'..='\u{1F53D}' | '\u{1F54B}'..='\u{1F54E}'
+        | '\u{1F550}'..='\u{1F567}' | '\u{1F57A}' | '\u{1F595}'..='\u{1F596}' | '\u{1F5A4}'
+        | '\u{1F5FB}'..='\u{1F64F}' | '\u{1F680}'..='\u{1F6C5}' | '\u{1F6CC}'
+        | '\u{1F6D0}'..='\u{1F6D2}' | '\u{1F6D5}'..='\u{1F6D7}' | '\u{1F6DC}'..='\u{1F6DF}'
+        | '\u{1F6EB}'..='\u{1F6EC}' | '\u{1F6F4}'..='\u{1F6FC}' | '\u{1F7E0}'..='\u{1F7EB}'
+        | '\u{1F7F0}' | '\u{1F90C}'..='\u{1F93A}' | '\u{1F93C}'..='\u{1F945}'
+        | '\u{1F947}'..='\u{1F9FF}' | '\u{1FA70}'..='\u{1FAFF}'
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clusters(word: &str) -> Vec<&str> {
+        emoji_clusters(word)
+            .into_iter()
+            .map(|(start, end)| &word[start..end])
+            .collect()
+    }
+
+    #[test]
+    fn finds_emoji_presented_as_emoji() {
+        assert_eq!(clusters("hi😀"), ["😀"]);
+        assert_eq!(clusters("⌚⭐"), ["⌚", "⭐"]);
+        assert_eq!(clusters("👍🏽"), ["👍🏽"]);
+        assert_eq!(clusters("👩‍💻!"), ["👩‍💻"]);
+        assert_eq!(clusters("🇯🇵"), ["🇯🇵"]);
+        assert_eq!(clusters("1\u{FE0F}\u{20E3}"), ["1\u{FE0F}\u{20E3}"]);
+    }
+
+    #[test]
+    fn leaves_text_symbols_as_text() {
+        assert!(clusters("©2024 Brand™ ® ↔ ‼ ℹ ☺ 🌡").is_empty());
+        assert_eq!(clusters("©\u{FE0F}"), ["©\u{FE0F}"]);
+        assert_eq!(clusters("❤\u{FE0F}text"), ["❤\u{FE0F}"]);
+        assert_eq!(clusters("❤"), Vec::<&str>::new());
+    }
+}
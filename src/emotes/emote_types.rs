@@ -1,7 +1,12 @@
+use std::collections::HashMap;
+
 use bevy::{asset::Handle, prelude::Image, render::texture::ImageFormat};
 use serde::{Deserialize, Serialize};
 use vleue_kinetoscope::AnimatedImage;
 
+/// 7TV emote flag for emotes drawn over the previous emote, eg hats
+const SEVEN_TV_ZERO_WIDTH_FLAG: u32 = 1 << 8;
+
 pub(crate) struct EmoteMeta {
     pub(crate) width: u32,
     pub(crate) height: u32,
@@ -11,6 +16,8 @@ pub(crate) struct EmoteMeta {
 pub(crate) struct EmoteHandles {
     pub(crate) animated_image: Option<Handle<AnimatedImage>>,
     pub(crate) static_image: Option<Handle<Image>>,
+    /// When the emote was last shown, from `EmoteStorage::next_use`
+    pub(crate) last_used: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -22,20 +29,36 @@ pub(crate) struct Emote {
     pub(crate) format: Option<ImageFormat>,
     pub(crate) width: Option<u32>,
     pub(crate) height: Option<u32>,
+    /// Drawn over the previous emote instead of taking its own space
+    pub(crate) zero_width: bool,
+    /// Asset path of the image in the emote cache, if it has been cached
+    pub(crate) cache_path: Option<String>,
 }
 
 impl Emote {
-    pub(crate) fn add_animated(&self, handle: Handle<AnimatedImage>) -> EmoteHandles {
+    /// Whether the size, and format of static emotes, is known so the emote can be displayed
+    pub(crate) fn is_resolved(&self) -> bool {
+        let has_size = self.width.is_some() && self.height.is_some_and(|height| height > 0);
+        has_size && (self.animated || self.format.is_some())
+    }
+
+    pub(crate) fn add_animated(
+        &self,
+        handle: Handle<AnimatedImage>,
+        last_used: u64,
+    ) -> EmoteHandles {
         EmoteHandles {
             animated_image: Some(handle),
             static_image: None,
+            last_used,
         }
     }
 
-    pub(crate) fn add_static(&self, handle: Handle<Image>) -> EmoteHandles {
+    pub(crate) fn add_static(&self, handle: Handle<Image>, last_used: u64) -> EmoteHandles {
         EmoteHandles {
             animated_image: None,
             static_image: Some(handle),
+            last_used,
         }
     }
 }
@@ -53,6 +76,35 @@ impl From<twitch_irc::message::Emote> for Emote {
             format: None,
             width: None,
             height: None,
+            zero_width: false,
+            cache_path: None,
+        }
+    }
+}
+
+/// Custom emote parsed from a Discord message, eg `<a:name:id>`
+pub(crate) struct DiscordEmote {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) animated: bool,
+}
+
+impl From<DiscordEmote> for Emote {
+    fn from(emote: DiscordEmote) -> Self {
+        let extension = if emote.animated { "gif" } else { "png" };
+        Self {
+            _id: emote.id.clone(),
+            name: emote.name,
+            animated: emote.animated,
+            emote_url: format!(
+                "https://cdn.discordapp.com/emojis/{}.{}",
+                emote.id, extension
+            ),
+            format: None,
+            width: None,
+            height: None,
+            zero_width: false,
+            cache_path: None,
         }
     }
 }
@@ -75,6 +127,8 @@ impl From<RawSevenTVEmote> for Emote {
                 format: Some(ImageFormat::WebP),
                 width: Some(file.width),
                 height: Some(file.height),
+                zero_width: raw_emote.flags & SEVEN_TV_ZERO_WIDTH_FLAG != 0,
+                cache_path: None,
             }
         } else {
             // Use technical difficulties emote if no files are found
@@ -88,6 +142,8 @@ impl From<RawSevenTVEmote> for Emote {
                 format: Some(ImageFormat::WebP),
                 width: Some(128),
                 height: Some(128),
+                zero_width: false,
+                cache_path: None,
             }
         }
     }
@@ -100,18 +156,41 @@ pub(crate) struct SevenTVResponse {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct SevenTVEmoteSet {
+    pub id: String,
     pub emotes: Vec<SevenTVEmoteBundle>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// An emote in a 7TV emote set. `name` is the name used in the set, which can differ from
+/// the original emote name
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct SevenTVEmoteBundle {
+    pub id: String,
+    pub name: String,
     pub data: RawSevenTVEmote,
 }
 
+impl From<SevenTVEmoteBundle> for Emote {
+    fn from(bundle: SevenTVEmoteBundle) -> Self {
+        Self {
+            name: bundle.name,
+            ..Emote::from(bundle.data)
+        }
+    }
+}
+
+/// An emote in a 7TV emote set change where only the id and name are needed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct SevenTVEmoteRef {
+    pub id: String,
+    pub name: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct RawSevenTVEmote {
     pub id: String,
     pub name: String,
+    #[serde(default)]
+    pub flags: u32,
     pub animated: bool,
     pub host: SevenTVEmoteHost,
 }
@@ -129,3 +208,108 @@ pub(crate) struct SevenTVEmoteFile {
     pub width: u32,
     pub height: u32,
 }
+
+/// Emote from the BetterTTV API. Sizes are not included so they are fetched from the image
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct BttvEmote {
+    pub id: String,
+    pub code: String,
+    pub animated: bool,
+}
+
+impl From<BttvEmote> for Emote {
+    fn from(emote: BttvEmote) -> Self {
+        Self {
+            _id: emote.id.clone(),
+            name: emote.code,
+            animated: emote.animated,
+            emote_url: format!("https://cdn.betterttv.net/emote/{}/3x", emote.id),
+            format: None,
+            width: None,
+            height: None,
+            zero_width: false,
+            cache_path: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BttvUserResponse {
+    pub channel_emotes: Vec<BttvEmote>,
+    pub shared_emotes: Vec<BttvEmote>,
+}
+
+impl From<RawFfzEmote> for Emote {
+    fn from(raw_emote: RawFfzEmote) -> Self {
+        // Animated emotes have separate webp urls, static emotes are png
+        let (urls, animated, format) = match raw_emote.animated {
+            Some(urls) => (urls, true, ImageFormat::WebP),
+            None => (raw_emote.urls, false, ImageFormat::Png),
+        };
+        // Urls are keyed by scale, the emote size is given at scale 1
+        let largest_scale_url = urls
+            .into_iter()
+            .filter_map(|(scale, url)| Some((scale.parse::<u32>().ok()?, url?)))
+            .max_by_key(|(scale, _)| *scale);
+        match largest_scale_url {
+            Some((scale, url)) => Self {
+                _id: raw_emote.id.to_string(),
+                name: raw_emote.name,
+                animated,
+                emote_url: url,
+                format: Some(format),
+                width: Some(raw_emote.width * scale),
+                height: Some(raw_emote.height * scale),
+                zero_width: false,
+                cache_path: None,
+            },
+            // Fall back to the cdn url at scale 1
+            None => Self {
+                _id: raw_emote.id.to_string(),
+                name: raw_emote.name,
+                animated: false,
+                emote_url: format!("https://cdn.frankerfacez.com/emote/{}/1", raw_emote.id),
+                format: Some(ImageFormat::Png),
+                width: Some(raw_emote.width),
+                height: Some(raw_emote.height),
+                zero_width: false,
+                cache_path: None,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct FfzGlobalResponse {
+    /// Sets available to every user, `sets` can also contain sets for specific users
+    pub default_sets: Vec<u64>,
+    pub sets: HashMap<String, FfzEmoteSet>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct FfzRoomResponse {
+    pub room: FfzRoom,
+    pub sets: HashMap<String, FfzEmoteSet>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct FfzRoom {
+    pub set: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct FfzEmoteSet {
+    pub emoticons: Vec<RawFfzEmote>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RawFfzEmote {
+    pub id: u64,
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub urls: HashMap<String, Option<String>>,
+    #[serde(default)]
+    pub animated: Option<HashMap<String, Option<String>>>,
+}
@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use bevy::{
+    asset::{Assets, Handle, UntypedAssetId},
+    prelude::{Image, Local, Query, Res, ResMut},
+    time::Time,
+    utils::HashSet,
+};
+use log::info;
+use vleue_kinetoscope::AnimatedImage;
+
+use crate::{config::Config, emotes::emote_types::EmoteHandles, EmoteStorage};
+
+const USAGE_REPORT_INTERVAL: Duration = Duration::from_secs(300);
+
+/// System to release the images of emotes which are no longer on screen once more emotes are
+/// loaded, or more image memory is used, than the config allows. The least recently shown
+/// emotes are released first and are loaded again if they are used later
+pub(crate) fn evict_emotes(
+    mut emote_store: ResMut<EmoteStorage>,
+    config: Res<Config>,
+    images: Res<Assets<Image>>,
+    static_query: Query<&Handle<Image>>,
+    animated_query: Query<&Handle<AnimatedImage>>,
+    time: Res<Time>,
+    mut last_report: Local<Duration>,
+) {
+    // Includes avatars and every frame of animated emotes
+    let image_memory: u64 = images
+        .iter()
+        .map(|(_, image)| image.data.len() as u64)
+        .sum();
+    let excess_emotes = emote_store
+        .loaded
+        .len()
+        .saturating_sub(config.max_loaded_emotes);
+    // Memory is only freed once the images are dropped, so one emote is released per frame
+    // until the memory use is back under the limit
+    let evict_count = excess_emotes.max((image_memory > config.max_image_memory) as usize);
+
+    if evict_count > 0 {
+        let in_use: HashSet<UntypedAssetId> = static_query
+            .iter()
+            .map(|handle| handle.id().untyped())
+            .chain(animated_query.iter().map(|handle| handle.id().untyped()))
+            .collect();
+        let mut unused: Vec<(u64, String)> = emote_store
+            .loaded
+            .iter()
+            .filter(|(_, handles)| !is_in_use(handles, &in_use))
+            .map(|(url, handles)| (handles.last_used, url.clone()))
+            .collect();
+        unused.sort_unstable();
+
+        let evicted = unused.len().min(evict_count);
+        for (_, url) in unused.into_iter().take(evicted) {
+            emote_store.loaded.remove(&url);
+        }
+        if evicted > 0 {
+            info!("Released {} unused emote images", evicted);
+            report_usage(&emote_store, image_memory);
+            *last_report = time.elapsed();
+        }
+    }
+
+    if time.elapsed().saturating_sub(*last_report) >= USAGE_REPORT_INTERVAL {
+        report_usage(&emote_store, image_memory);
+        *last_report = time.elapsed();
+    }
+}
+
+/// Whether a message entity is showing the emote
+fn is_in_use(handles: &EmoteHandles, in_use: &HashSet<UntypedAssetId>) -> bool {
+    let animated = handles
+        .animated_image
+        .as_ref()
+        .map(|handle| handle.id().untyped());
+    let image = handles
+        .static_image
+        .as_ref()
+        .map(|handle| handle.id().untyped());
+    animated
+        .into_iter()
+        .chain(image)
+        .any(|id| in_use.contains(&id))
+}
+
+fn report_usage(emote_store: &EmoteStorage, image_memory: u64) {
+    info!(
+        "Emote images: {} loaded, {:.1} MB of image memory in use",
+        emote_store.loaded.len(),
+        image_memory as f64 / (1024.0 * 1024.0)
+    );
+}
@@ -1,96 +1,774 @@
+pub mod cache;
+pub mod emoji;
 pub mod emote_types;
+pub mod eviction;
+use std::{str::FromStr, time::Duration};
+
 use bevy::{render::texture::ImageFormat, utils::HashMap};
+use futures_util::{stream, StreamExt};
 use log::{info, warn};
+use serde::de::DeserializeOwned;
+use tokio::time::timeout;
 
-use crate::emotes::emote_types::{Emote, EmoteMeta, SevenTVResponse};
+use crate::{
+    emotes::cache::EmoteCache,
+    emotes::emote_types::{
+        BttvEmote, BttvUserResponse, Emote, EmoteMeta, FfzEmoteSet, FfzGlobalResponse,
+        FfzRoomResponse, SevenTVEmoteSet, SevenTVResponse,
+    },
+    EmoteStorage,
+};
 
 const SEVEN_TV_URL: &str = "https://7tv.io/v3/users/twitch/";
+const SEVEN_TV_GLOBAL_URL: &str = "https://7tv.io/v3/emote-sets/global";
+const BTTV_GLOBAL_URL: &str = "https://api.betterttv.net/3/cached/emotes/global";
+const BTTV_USER_URL: &str = "https://api.betterttv.net/3/cached/users/twitch/";
+const FFZ_GLOBAL_URL: &str = "https://api.frankerfacez.com/v1/set/global";
+const FFZ_ROOM_URL: &str = "https://api.frankerfacez.com/v1/room/id/";
 
-pub(crate) async fn get_seventv_emotes(channel_id: String) -> HashMap<String, Emote> {
-    info!("Getting the 7TV channel emotes");
-    let response = reqwest::get(format!("{}{}", SEVEN_TV_URL, channel_id)).await;
-    if response.is_err() {
-        panic!("Cannot get 7tv emotes");
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of emotes whose size can be fetched at once
+pub(crate) const EMOTE_META_WORKERS: usize = 4;
+pub(crate) const EMOTE_META_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) type EmoteError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A third party emote service
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EmoteProvider {
+    SevenTV,
+    BetterTTV,
+    FrankerFaceZ,
+}
+
+impl FromStr for EmoteProvider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "7tv" => Ok(Self::SevenTV),
+            "bttv" => Ok(Self::BetterTTV),
+            "ffz" => Ok(Self::FrankerFaceZ),
+            other => Err(format!("Unknown emote provider {}", other)),
+        }
+    }
+}
+
+/// Emote providers in order of precedence, parsed from a comma separated list eg `7tv, bttv`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EmoteProviders(pub(crate) Vec<EmoteProvider>);
+
+impl FromStr for EmoteProviders {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut providers = vec![];
+        for provider in s.split(',').filter(|provider| !provider.trim().is_empty()) {
+            let provider = provider.parse::<EmoteProvider>()?;
+            if !providers.contains(&provider) {
+                providers.push(provider);
+            }
+        }
+        Ok(Self(providers))
+    }
+}
+
+/// Emotes loaded for a channel from every provider
+pub(crate) struct ChannelEmotes {
+    pub(crate) channel_id: String,
+    pub(crate) emotes: HashMap<String, Emote>,
+    /// Id of the channel's 7TV emote set, used to watch it for changes
+    pub(crate) seventv_emote_set: Option<String>,
+    /// Why the 7TV channel emotes could not be loaded, if they failed in a way which loading
+    /// them again may fix, eg 7TV could not be reached
+    pub(crate) seventv_error: Option<String>,
+}
+
+/// Global and channel emotes of one provider
+#[derive(Default)]
+pub(crate) struct ProviderEmotes {
+    pub(crate) global: HashMap<String, Emote>,
+    /// Emotes added by the channel, including BTTV emotes shared with it
+    pub(crate) channel: HashMap<String, Emote>,
+}
+
+/// Get the emotes of every provider. Channel emotes take precedence over global emotes, and
+/// when several providers have an emote with the same name the one earliest in `providers` is
+/// used
+pub(crate) async fn get_channel_emotes(
+    channel_id: String,
+    providers: &[EmoteProvider],
+    cache: &EmoteCache,
+) -> ChannelEmotes {
+    let mut seventv_emote_set = None;
+    let mut seventv_error = None;
+    let mut provider_emotes = vec![];
+    for provider in providers {
+        provider_emotes.push(match provider {
+            EmoteProvider::SevenTV => match get_seventv_emotes(channel_id.clone()).await {
+                Ok((seventv_emotes, emote_set_id)) => {
+                    seventv_emote_set = emote_set_id;
+                    seventv_emotes
+                }
+                Err(err) => {
+                    warn!("Cannot get the 7TV channel emotes: {}", err);
+                    if is_temporary(&err) {
+                        seventv_error = Some(err.to_string());
+                    }
+                    ProviderEmotes::default()
+                }
+            },
+            EmoteProvider::BetterTTV => get_bttv_emotes(&channel_id, cache).await,
+            EmoteProvider::FrankerFaceZ => get_ffz_emotes(&channel_id).await,
+        });
+    }
+    let mut emotes = merge_provider_emotes(provider_emotes);
+    // Use the cached images of emotes seen on previous runs
+    for emote in emotes
+        .values_mut()
+        .filter(|emote| emote.cache_path.is_none())
+    {
+        cache.apply(emote).await;
+    }
+    ChannelEmotes {
+        channel_id,
+        emotes,
+        seventv_emote_set,
+        seventv_error,
+    }
+}
+
+/// Combine the emotes of each provider, given in order of precedence. Any channel emote is used
+/// over a global emote with the same name, then the emote of the earliest provider is used
+fn merge_provider_emotes(provider_emotes: Vec<ProviderEmotes>) -> HashMap<String, Emote> {
+    let (global, channel): (Vec<_>, Vec<_>) = provider_emotes
+        .into_iter()
+        .map(|emotes| (emotes.global, emotes.channel))
+        .unzip();
+    let mut emotes = HashMap::new();
+    // Later providers are overwritten by earlier ones, and global emotes by channel emotes
+    for provider_emotes in global.into_iter().rev().chain(channel.into_iter().rev()) {
+        emotes.extend(provider_emotes);
+    }
+    emotes
+}
+
+/// A change to the channel's 7TV emote set made while running
+#[derive(Debug)]
+pub(crate) enum EmoteSetChange {
+    Added(Emote),
+    Removed {
+        id: String,
+        name: String,
+    },
+    Renamed {
+        id: String,
+        old_name: String,
+        new_name: String,
+    },
+}
+
+/// Replace the emote provider emotes with a newly loaded set. Images of emotes which were
+/// dropped or now use a different image are dropped with them
+pub(crate) fn replace_channel_emotes(
+    emote_store: &mut EmoteStorage,
+    emotes: HashMap<String, Emote>,
+) {
+    let previous = std::mem::replace(&mut emote_store.all, emotes);
+    for (name, emote) in previous {
+        let kept = emote_store
+            .all
+            .get(&name)
+            .is_some_and(|new_emote| new_emote.emote_url == emote.emote_url);
+        if !kept {
+            emote_store.loaded.remove(&emote.emote_url);
+        }
+    }
+}
+
+/// Update the stored emotes with a change to the 7TV emote set. Emotes with the same name from
+/// other providers are left alone when a 7TV emote is removed or renamed
+pub(crate) fn apply_emote_set_change(emote_store: &mut EmoteStorage, change: EmoteSetChange) {
+    match change {
+        EmoteSetChange::Added(emote) => {
+            info!("7TV emote added: {}", emote.name);
+            // Drop the image of any emote previously using the name
+            if let Some(previous) = emote_store.all.insert(emote.name.clone(), emote) {
+                emote_store.loaded.remove(&previous.emote_url);
+            }
+        }
+        EmoteSetChange::Removed { id, name } => {
+            if emote_store
+                .all
+                .get(&name)
+                .is_some_and(|emote| emote._id == id)
+            {
+                info!("7TV emote removed: {}", name);
+                if let Some(emote) = emote_store.all.remove(&name) {
+                    emote_store.loaded.remove(&emote.emote_url);
+                }
+            }
+        }
+        EmoteSetChange::Renamed {
+            id,
+            old_name,
+            new_name,
+        } => {
+            if emote_store.all.get(&old_name).map(|emote| &emote._id) != Some(&id) {
+                return;
+            }
+            info!("7TV emote renamed: {} to {}", old_name, new_name);
+            // The image is the same so it is kept
+            if let Some(mut emote) = emote_store.all.remove(&old_name) {
+                emote.name = new_name.clone();
+                if let Some(previous) = emote_store.all.insert(new_name, emote) {
+                    emote_store.loaded.remove(&previous.emote_url);
+                }
+            }
+        }
+    }
+}
+
+/// Store an emote sent with a chat message. An emote already stored with the same id is kept
+/// unless its size was not known yet. Returns true if the emote can now be displayed where it was
+/// shown as text before
+pub(crate) fn store_chat_emote(emote_store: &mut EmoteStorage, emote: Emote) -> bool {
+    match emote_store.chat.get_mut(&emote._id) {
+        Some(stored) => {
+            if stored.is_resolved() || !emote.is_resolved() {
+                return false;
+            }
+            *stored = emote;
+            true
+        }
+        None => {
+            let resolved = emote.is_resolved();
+            emote_store.chat.insert(emote._id.clone(), emote);
+            resolved
+        }
     }
+}
+
+/// Get the 7TV global and channel emotes, and the id of the channel emote set if the channel has
+/// one. Fails if the channel emotes cannot be loaded
+pub(crate) async fn get_seventv_emotes(
+    channel_id: String,
+) -> Result<(ProviderEmotes, Option<String>), EmoteError> {
+    info!("Getting the 7TV global emotes");
+    let global: HashMap<String, Emote> =
+        get_provider_json::<SevenTVEmoteSet>("7TV", SEVEN_TV_GLOBAL_URL)
+            .await
+            .map(|emote_set| seventv_set_emotes(&emote_set))
+            .unwrap_or_default();
+
+    info!("Getting the 7TV channel emotes");
+    let response: SevenTVResponse =
+        match fetch_provider_json(&format!("{}{}", SEVEN_TV_URL, channel_id)).await {
+            Ok(response) => response,
+            // Channels without 7TV respond with 404
+            Err(err) if is_not_found(&err) => {
+                info!("Channel {} has no 7TV emotes", channel_id);
+                let emotes = ProviderEmotes {
+                    global,
+                    channel: HashMap::new(),
+                };
+                return Ok((emotes, None));
+            }
+            Err(err) => return Err(err),
+        };
+    let emotes = ProviderEmotes {
+        global,
+        channel: seventv_set_emotes(&response.emote_set),
+    };
+    Ok((emotes, Some(response.emote_set.id)))
+}
+
+/// Whether a request failed in a way which trying again later may fix: the provider could not
+/// be reached, took too long to respond or had a server error
+fn is_temporary(err: &EmoteError) -> bool {
+    err.downcast_ref::<reqwest::Error>().is_some_and(|err| {
+        err.is_connect()
+            || err.is_timeout()
+            || err.status().is_some_and(|status| status.is_server_error())
+    })
+}
 
-    let response: SevenTVResponse = response.unwrap().json::<SevenTVResponse>().await.unwrap();
-    response
-        .emote_set
+/// Whether a request failed because there is nothing at the url
+fn is_not_found(err: &EmoteError) -> bool {
+    err.downcast_ref::<reqwest::Error>()
+        .and_then(|err| err.status())
+        .is_some_and(|status| status == reqwest::StatusCode::NOT_FOUND)
+}
+
+fn seventv_set_emotes(emote_set: &SevenTVEmoteSet) -> HashMap<String, Emote> {
+    emote_set
         .emotes
         .iter()
-        .map(|emote| (emote.data.name.clone(), Emote::from(emote.data.clone())))
+        .map(|emote| (emote.name.clone(), Emote::from(emote.clone())))
         .collect()
 }
 
-async fn get_image_meta(url: &str) -> EmoteMeta {
-    // Initialize an HTTP client
-    let client = reqwest::Client::new();
+/// Get the BTTV global emotes, and the shared and channel emotes of the channel
+pub(crate) async fn get_bttv_emotes(channel_id: &str, cache: &EmoteCache) -> ProviderEmotes {
+    info!("Getting the BTTV emotes");
+    let global_emotes: Vec<BttvEmote> = get_provider_json("BTTV", BTTV_GLOBAL_URL)
+        .await
+        .unwrap_or_default();
+    // Channels without BTTV respond with 404
+    let user_emotes: Option<BttvUserResponse> =
+        get_provider_json("BTTV", &format!("{}{}", BTTV_USER_URL, channel_id)).await;
 
-    // Fetch the first 4096 bytes (this should be enough for most image formats)
-    let response = client
-        .get(url)
-        .header(reqwest::header::RANGE, "bytes=0-8096")
-        .send()
+    // BTTV does not give emote sizes so they are read from the images
+    let emotes = bttv_emotes(global_emotes, user_emotes);
+    ProviderEmotes {
+        global: read_provider_emote_meta("BTTV", cache, emotes.global).await,
+        channel: read_provider_emote_meta("BTTV", cache, emotes.channel).await,
+    }
+}
+
+/// Every BTTV emote of a channel by name. Shared emotes count as channel emotes, with the
+/// channel's own emotes taking precedence over them
+fn bttv_emotes(
+    global_emotes: Vec<BttvEmote>,
+    user_emotes: Option<BttvUserResponse>,
+) -> ProviderEmotes {
+    let by_name = |emotes: Vec<BttvEmote>| -> HashMap<String, Emote> {
+        emotes
+            .into_iter()
+            .map(|emote| (emote.code.clone(), Emote::from(emote)))
+            .collect()
+    };
+    let mut channel = HashMap::new();
+    if let Some(user_emotes) = user_emotes {
+        channel.extend(by_name(user_emotes.shared_emotes));
+        channel.extend(by_name(user_emotes.channel_emotes));
+    }
+    ProviderEmotes {
+        global: by_name(global_emotes),
+        channel,
+    }
+}
+
+/// Fill in the size and format of emotes from the emote cache, or else from the header of their
+/// image. A few images are read at a time and slow images are given up on, leaving the emote
+/// shown as text
+async fn read_provider_emote_meta(
+    provider: &str,
+    cache: &EmoteCache,
+    emotes: HashMap<String, Emote>,
+) -> HashMap<String, Emote> {
+    stream::iter(emotes)
+        .map(|(name, mut emote)| async move {
+            if cache.apply(&mut emote).await {
+                return (name, emote);
+            }
+            match timeout(EMOTE_META_TIMEOUT, read_emote_meta(&mut emote)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => {
+                    warn!(
+                        "Cannot get the size of {} emote {}: {}",
+                        provider, emote.name, err
+                    )
+                }
+                Err(_) => warn!(
+                    "Timed out getting the size of {} emote {}",
+                    provider, emote.name
+                ),
+            }
+            (name, emote)
+        })
+        .buffer_unordered(EMOTE_META_WORKERS)
+        .collect()
         .await
-        .expect("Successful emote header request")
-        .bytes();
+}
 
-    // Create a cursor to read the in-memory byte stream
-    let cursor = std::io::Cursor::new(response);
+/// Get the FFZ global emotes and the emotes of the channel room
+pub(crate) async fn get_ffz_emotes(channel_id: &str) -> ProviderEmotes {
+    info!("Getting the FFZ emotes");
+    let mut global_sets: Vec<FfzEmoteSet> = vec![];
+    if let Some(mut global) = get_provider_json::<FfzGlobalResponse>("FFZ", FFZ_GLOBAL_URL).await {
+        for set_id in global.default_sets.iter() {
+            global_sets.extend(global.sets.remove(&set_id.to_string()));
+        }
+    }
+    // Channels without FFZ respond with 404
+    let room_set =
+        get_provider_json::<FfzRoomResponse>("FFZ", &format!("{}{}", FFZ_ROOM_URL, channel_id))
+            .await
+            .and_then(|mut room| room.sets.remove(&room.room.set.to_string()));
 
-    // Use the `image` crate to read the image dimensions from the cursor
-    let bytes = cursor.into_inner().await.unwrap();
-    let reader = image::ImageReader::new(std::io::Cursor::new(bytes))
-        .with_guessed_format()
-        .expect("Guessed format");
+    ProviderEmotes {
+        global: ffz_set_emotes(global_sets),
+        channel: ffz_set_emotes(room_set),
+    }
+}
 
-    let image_format = reader.format().expect("Image format from metadata");
+fn ffz_set_emotes(sets: impl IntoIterator<Item = FfzEmoteSet>) -> HashMap<String, Emote> {
+    sets.into_iter()
+        .flat_map(|set| set.emoticons)
+        .map(|raw_emote| (raw_emote.name.clone(), Emote::from(raw_emote)))
+        .collect()
+}
 
-    // Decode the image header to get the dimensions
-    let dimensions = match reader.into_dimensions() {
-        Ok(dimensions) => dimensions,
+/// Get a JSON response from an emote provider, logging any failure
+async fn get_provider_json<T: DeserializeOwned>(provider: &str, url: &str) -> Option<T> {
+    match fetch_provider_json(url).await {
+        Ok(json) => Some(json),
         Err(err) => {
-            warn!("Error reading image dimensions: {}", err);
-            warn!("URL: {}", url);
-            return EmoteMeta {
-                width: 0,
-                height: 0,
-                format: ImageFormat::Png,
-            };
+            warn!("{} request to {} failed: {}", provider, url, err);
+            None
         }
-    };
-    let format = ImageFormat::from_image_crate_format(image_format)
-        .expect("Image format converted to Bevy format");
+    }
+}
+
+/// Get a JSON response from an emote provider. Slow responses time out so a provider which is
+/// down does not hold up loading the other emotes
+async fn fetch_provider_json<T: DeserializeOwned>(url: &str) -> Result<T, EmoteError> {
+    let response = reqwest::Client::builder()
+        .timeout(PROVIDER_TIMEOUT)
+        .build()?
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(response.json::<T>().await?)
+}
+
+/// Read the size and format of an image from the start of the file
+async fn get_image_meta(url: &str) -> Result<EmoteMeta, EmoteError> {
+    // Fetch the first 8KB, enough for the header of most image formats
+    let bytes = reqwest::Client::new()
+        .get(url)
+        .header(reqwest::header::RANGE, "bytes=0-8096")
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
 
-    // debug!("Image format: {:?}", image_format);
-    // debug!("Width: {}, Height: {}", dimensions.0, dimensions.1);
-    // debug!("Format: {:?}", format);
+    let reader = image::ImageReader::new(std::io::Cursor::new(bytes)).with_guessed_format()?;
+    let image_format = reader.format().ok_or("Unknown image format")?;
+    let (width, height) = reader.into_dimensions()?;
+    let format = ImageFormat::from_image_crate_format(image_format)
+        .ok_or("Image format not supported by Bevy")?;
 
-    EmoteMeta {
-        width: dimensions.0,
-        height: dimensions.1,
+    Ok(EmoteMeta {
+        width,
+        height,
         format,
+    })
+}
+
+/// Fill in the size and format of an emote, from the emote cache if possible. If the emote
+/// cannot be cached only the image header is read
+pub(crate) async fn update_emote_meta(
+    cache: &EmoteCache,
+    emote: &mut Emote,
+) -> Result<(), EmoteError> {
+    if cache.apply(emote).await {
+        return Ok(());
     }
+    match cache.store(emote).await {
+        Ok(()) => return Ok(()),
+        Err(err) => warn!("Cannot cache emote {}: {}", emote.name, err),
+    }
+    read_emote_meta(emote).await
 }
 
-pub(crate) async fn update_emote_meta(emote: &mut Emote) {
-    let meta = get_image_meta(&emote.emote_url).await;
+/// Fill in the size and format of an emote from the header of its image
+async fn read_emote_meta(emote: &mut Emote) -> Result<(), EmoteError> {
+    let meta = get_image_meta(&emote.emote_url).await?;
+    if meta.width == 0 || meta.height == 0 {
+        return Err(format!("Emote image {} has no size", emote.emote_url).into());
+    }
+    emote.animated = match meta.format {
+        ImageFormat::Png => false,
+        ImageFormat::Gif | ImageFormat::WebP => true,
+        _ => {
+            warn!("Unsupported image format: {:?}", meta.format);
+            false
+        }
+    };
     emote.width = Some(meta.width);
     emote.height = Some(meta.height);
     emote.format = Some(meta.format);
-    match meta.format {
-        ImageFormat::Png => {
-            emote.animated = false;
-        }
-        ImageFormat::Gif => {
-            emote.animated = true;
-        }
-        ImageFormat::WebP => {
-            emote.animated = true;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BTTV_GLOBAL: &str = include_str!("../../tests/fixtures/bttv_global.json");
+    const BTTV_USER: &str = include_str!("../../tests/fixtures/bttv_user.json");
+    const FFZ_ROOM: &str = include_str!("../../tests/fixtures/ffz_room.json");
+
+    fn recorded_bttv_emotes() -> ProviderEmotes {
+        let global: Vec<BttvEmote> = serde_json::from_str(BTTV_GLOBAL).unwrap();
+        let user: BttvUserResponse = serde_json::from_str(BTTV_USER).unwrap();
+        bttv_emotes(global, Some(user))
+    }
+
+    fn channel_emotes(provider: &str, names: &[&str]) -> ProviderEmotes {
+        ProviderEmotes {
+            global: HashMap::new(),
+            channel: emotes(provider, names),
         }
-        _ => {
-            warn!("Unsupported image format: {:?}", emote.format);
-            emote.animated = false;
+    }
+
+    fn emotes(provider: &str, names: &[&str]) -> HashMap<String, Emote> {
+        names
+            .iter()
+            .map(|name| {
+                let emote = Emote::from(BttvEmote {
+                    id: format!("{}-{}", provider, name),
+                    code: name.to_string(),
+                    animated: false,
+                });
+                (name.to_string(), emote)
+            })
+            .collect()
+    }
+
+    fn ids(emotes: &HashMap<String, Emote>) -> Vec<(&str, &str)> {
+        let mut ids: Vec<(&str, &str)> = emotes
+            .iter()
+            .map(|(name, emote)| (name.as_str(), emote._id.as_str()))
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn parses_bttv_user_emotes() {
+        let user: BttvUserResponse = serde_json::from_str(BTTV_USER).unwrap();
+        let codes = |emotes: &[BttvEmote]| -> Vec<String> {
+            emotes.iter().map(|emote| emote.code.clone()).collect()
+        };
+        assert_eq!(codes(&user.channel_emotes), ["catJAM", "SourPls"]);
+        assert_eq!(codes(&user.shared_emotes), ["monkaS", "catJAM"]);
+        assert!(user.channel_emotes[0].animated);
+        assert!(!user.shared_emotes[0].animated);
+    }
+
+    #[test]
+    fn bttv_channel_emotes_take_precedence() {
+        let emotes = recorded_bttv_emotes();
+        assert_eq!(emotes.global.len(), 4);
+        assert_eq!(emotes.global["SourPls"]._id, "566ca38765dbbdab32ec0560");
+        // Shared emotes are channel emotes, below the channel's own emotes
+        assert_eq!(
+            ids(&emotes.channel),
+            [
+                ("SourPls", "60ae958e229664e8667aea38"),
+                ("catJAM", "5f1b0186cf6d2144653d2970"),
+                ("monkaS", "5b1740221c5a6065a7bad4b5")
+            ]
+        );
+        assert_eq!(
+            emotes.channel["catJAM"].emote_url,
+            "https://cdn.betterttv.net/emote/5f1b0186cf6d2144653d2970/3x"
+        );
+        // Sizes are read from the images later
+        assert!(!emotes.channel["catJAM"].is_resolved());
+
+        let merged = merge_provider_emotes(vec![emotes]);
+        assert_eq!(merged.len(), 6);
+        assert_eq!(merged["SourPls"]._id, "60ae958e229664e8667aea38");
+    }
+
+    #[test]
+    fn uses_the_largest_ffz_images() {
+        let mut room: FfzRoomResponse = serde_json::from_str(FFZ_ROOM).unwrap();
+        let emotes = ffz_set_emotes(room.sets.remove(&room.room.set.to_string()));
+        assert_eq!(emotes.len(), 3);
+
+        let omegalul = &emotes["OMEGALUL"];
+        assert_eq!(omegalul._id, "128054");
+        assert_eq!(
+            omegalul.emote_url,
+            "https://cdn.frankerfacez.com/emote/128054/4"
+        );
+        assert!(!omegalul.animated);
+        assert!(matches!(omegalul.format, Some(ImageFormat::Png)));
+        // Sizes are given at scale 1
+        assert_eq!((omegalul.width, omegalul.height), (Some(112), Some(84)));
+
+        // Animated images are used over static ones, skipping missing scales
+        let dance = &emotes["peepoHappyDance"];
+        assert_eq!(
+            dance.emote_url,
+            "https://cdn.frankerfacez.com/emote/720507/animated/2"
+        );
+        assert!(dance.animated);
+        assert!(matches!(dance.format, Some(ImageFormat::WebP)));
+        assert_eq!((dance.width, dance.height), (Some(64), Some(64)));
+
+        let cat_bag = &emotes["CatBag"];
+        assert_eq!(
+            cat_bag.emote_url,
+            "https://cdn.frankerfacez.com/emote/25927/1"
+        );
+        assert_eq!((cat_bag.width, cat_bag.height), (Some(32), Some(32)));
+    }
+
+    #[test]
+    fn earlier_providers_take_precedence() {
+        let merged = merge_provider_emotes(vec![
+            channel_emotes("7tv", &["catJAM"]),
+            recorded_bttv_emotes(),
+            channel_emotes("ffz", &["catJAM", "monkaS", "OMEGALUL"]),
+        ]);
+        assert_eq!(merged.len(), 7);
+        assert_eq!(merged["catJAM"]._id, "7tv-catJAM");
+        assert_eq!(merged["monkaS"]._id, "5b1740221c5a6065a7bad4b5");
+        assert_eq!(merged["OMEGALUL"]._id, "ffz-OMEGALUL");
+
+        let merged = merge_provider_emotes(vec![
+            channel_emotes("ffz", &["catJAM", "monkaS"]),
+            channel_emotes("7tv", &["catJAM", "peepoHey"]),
+        ]);
+        assert_eq!(
+            ids(&merged),
+            [
+                ("catJAM", "ffz-catJAM"),
+                ("monkaS", "ffz-monkaS"),
+                ("peepoHey", "7tv-peepoHey")
+            ]
+        );
+    }
+
+    #[test]
+    fn channel_emotes_take_precedence_over_every_global_emote() {
+        let merged = merge_provider_emotes(vec![
+            ProviderEmotes {
+                global: emotes("7tv", &["catJAM", "EZ"]),
+                channel: emotes("7tv", &["peepoHey"]),
+            },
+            ProviderEmotes {
+                global: emotes("bttv", &["EZ", "monkaS"]),
+                channel: emotes("bttv", &["catJAM"]),
+            },
+            ProviderEmotes {
+                global: emotes("ffz", &["monkaS", "peepoHey"]),
+                channel: emotes("ffz", &["EZ"]),
+            },
+        ]);
+        assert_eq!(
+            ids(&merged),
+            [
+                ("EZ", "ffz-EZ"),
+                ("catJAM", "bttv-catJAM"),
+                ("monkaS", "bttv-monkaS"),
+                ("peepoHey", "7tv-peepoHey")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn drops_images_of_replaced_channel_emotes() {
+        let mut emote_store = EmoteStorage::for_tests("emotes");
+        replace_channel_emotes(
+            &mut emote_store,
+            emotes("bttv", &["catJAM", "monkaS", "SourPls"]),
+        );
+        for emote in emote_store.all.values() {
+            let handles = emote.add_static(Default::default(), 0);
+            emote_store.loaded.insert(emote.emote_url.clone(), handles);
         }
+
+        // catJAM now comes from another provider and SourPls is gone
+        let mut reloaded = emotes("bttv", &["monkaS"]);
+        reloaded.extend(emotes("7tv", &["catJAM", "peepoHey"]));
+        replace_channel_emotes(&mut emote_store, reloaded);
+        assert_eq!(
+            ids(&emote_store.all),
+            [
+                ("catJAM", "7tv-catJAM"),
+                ("monkaS", "bttv-monkaS"),
+                ("peepoHey", "7tv-peepoHey")
+            ]
+        );
+        let loaded: Vec<&String> = emote_store.loaded.keys().collect();
+        assert_eq!(loaded, ["https://cdn.betterttv.net/emote/bttv-monkaS/3x"]);
+
+        replace_channel_emotes(&mut emote_store, HashMap::new());
+        assert!(emote_store.all.is_empty());
+        assert!(emote_store.loaded.is_empty());
+    }
+
+    /// Url of a stand-in provider answering one request with `status`
+    async fn provider_responding(status: &'static str) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status);
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        format!("http://{}/", address)
+    }
+
+    #[tokio::test]
+    async fn recognises_missing_channels() {
+        let url = provider_responding("404 Not Found").await;
+        let err = fetch_provider_json::<SevenTVResponse>(&url)
+            .await
+            .unwrap_err();
+        assert!(is_not_found(&err));
+        assert!(!is_temporary(&err));
+
+        let url = provider_responding("502 Bad Gateway").await;
+        let err = fetch_provider_json::<SevenTVResponse>(&url)
+            .await
+            .unwrap_err();
+        assert!(!is_not_found(&err));
+    }
+
+    #[tokio::test]
+    async fn retries_only_failures_which_may_pass() {
+        let url = provider_responding("503 Service Unavailable").await;
+        let err = fetch_provider_json::<SevenTVResponse>(&url)
+            .await
+            .unwrap_err();
+        assert!(is_temporary(&err));
+
+        // Nothing is listening once the listener is dropped
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let err = fetch_provider_json::<SevenTVResponse>(&url)
+            .await
+            .unwrap_err();
+        assert!(is_temporary(&err));
+
+        let url = provider_responding("403 Forbidden").await;
+        let err = fetch_provider_json::<SevenTVResponse>(&url)
+            .await
+            .unwrap_err();
+        assert!(!is_temporary(&err));
+        // Responses which are not a 7TV user cannot be read however often they are fetched
+        let url = provider_responding("200 OK").await;
+        let err = fetch_provider_json::<SevenTVResponse>(&url)
+            .await
+            .unwrap_err();
+        assert!(!is_temporary(&err));
+    }
+
+    #[test]
+    fn parses_provider_lists() {
+        let providers: EmoteProviders = "bttv, 7TV,,bttv ,ffz".parse().unwrap();
+        assert_eq!(
+            providers.0,
+            [
+                EmoteProvider::BetterTTV,
+                EmoteProvider::SevenTV,
+                EmoteProvider::FrankerFaceZ
+            ]
+        );
+        assert!("7tv, twitch".parse::<EmoteProviders>().is_err());
     }
 }
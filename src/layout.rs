@@ -0,0 +1,616 @@
+use bevy::math::{Vec2, Vec3};
+use log::debug;
+
+use crate::config::Config;
+
+const NBSP: &str = "\u{A0}";
+
+/// Width of text in the message font
+pub(crate) trait TextWidth {
+    fn width(&self, text: &str) -> f32;
+}
+
+/// Size of an emote image in pixels
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct EmoteSize {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+/// Part of a word
+#[derive(Debug, Clone)]
+pub(crate) enum Piece {
+    Text(String),
+    Emote(EmoteSize),
+}
+
+/// A part of a message to lay out
+#[derive(Debug, Clone)]
+pub(crate) enum Token {
+    /// Text and emotes with no space between them, eg a single emote or `hi😀`
+    Word(Vec<Piece>),
+    /// A zero-width emote drawn over the emote before it
+    Overlay(EmoteSize),
+}
+
+/// A line of message text, positioned from the top left of the message box. Lines are broken
+/// by the layout rather than by Bevy so emotes stay on the line they were measured on
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TextRun {
+    pub(crate) text: String,
+    pub(crate) position: Vec2,
+    /// Part of the sender's name shown above the message
+    pub(crate) is_name: bool,
+}
+
+/// Where to draw an emote, in message order
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct EmoteSlot {
+    pub(crate) translation: Vec3,
+    pub(crate) scale: f32,
+}
+
+/// Positions of everything in a message
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MessageLayout {
+    pub(crate) runs: Vec<TextRun>,
+    /// Relative to the top left of the message box. For a big emote the first slot is relative
+    /// to the avatar and the overlays after it are relative to the first
+    pub(crate) emotes: Vec<EmoteSlot>,
+    pub(crate) line_count: usize,
+    pub(crate) box_size: Vec2,
+    /// Top left of the message box relative to the avatar
+    pub(crate) box_position: Vec2,
+    /// The message is a single emote, with any overlays, shown large above the avatar
+    pub(crate) big_emote: bool,
+}
+
+/// Lay out a message, breaking it into lines which fit in the message box. `name` is shown on
+/// its own line above the message, except for messages shown as a big emote
+pub(crate) fn layout_message(
+    tokens: &[Token],
+    name: Option<&str>,
+    measure: &impl TextWidth,
+    config: &Config,
+) -> MessageLayout {
+    if let Some(layout) = layout_big_emote(tokens, config) {
+        return layout;
+    }
+
+    let mut lines = Lines::new(measure, config.message_box_width);
+    if let Some(name) = name {
+        lines.push_word(name);
+        lines.end_line();
+    }
+    let name_lines = lines.line_number;
+    let mut emotes: Vec<EmoteSlot> = vec![];
+    // The last emote if nothing has been written since
+    let mut last_emote: Option<EmoteSlot> = None;
+
+    for token in tokens {
+        let pieces = match token {
+            Token::Overlay(size) => {
+                if let Some(base) = last_emote {
+                    let slot = EmoteSlot {
+                        translation: base.translation + Vec3::new(0.0, 0.0, 0.1),
+                        scale: emote_scale(size, config),
+                    };
+                    emotes.push(slot);
+                    last_emote = Some(slot);
+                    continue;
+                }
+                // Nothing to draw over, so it takes its own space
+                &vec![Piece::Emote(*size)]
+            }
+            Token::Word(pieces) => pieces,
+        };
+
+        match pieces.as_slice() {
+            [Piece::Text(text)] => {
+                lines.push_word(text);
+                last_emote = None;
+            }
+            [Piece::Emote(size)] => {
+                let placeholder = emote_placeholder(size, config, measure);
+                lines.wrap_for(&placeholder);
+                let x = lines.push_emote(&placeholder);
+                lines.push(" ");
+                let slot = emote_slot(x, lines.line_number, size, config);
+                emotes.push(slot);
+                last_emote = Some(slot);
+            }
+            _ => {
+                // Emotes take up non-breaking spaces so the word is kept together
+                let texts: Vec<String> = pieces
+                    .iter()
+                    .map(|piece| match piece {
+                        Piece::Text(text) => text.clone(),
+                        Piece::Emote(size) => emote_placeholder(size, config, measure),
+                    })
+                    .collect();
+                let word = texts.concat();
+                if measure.width(&word) <= config.message_box_width {
+                    lines.wrap_for(&format!("{} ", word));
+                }
+                // Words wider than a whole line are broken like text words, with each emote
+                // kept whole
+                for (piece, text) in pieces.iter().zip(texts) {
+                    match piece {
+                        Piece::Text(_) => {
+                            lines.push_chars(&text);
+                            last_emote = None;
+                        }
+                        Piece::Emote(size) => {
+                            lines.wrap_for(&text);
+                            let x = lines.push_emote(&text);
+                            let slot = emote_slot(x, lines.line_number, size, config);
+                            emotes.push(slot);
+                            last_emote = Some(slot);
+                        }
+                    }
+                }
+                lines.push(" ");
+            }
+        }
+    }
+
+    let runs = lines.finish(name_lines, config);
+    let line_count = runs.len().max(1);
+    let box_size = Vec2::new(
+        config.message_box_width,
+        line_count as f32 * (config.font_height() + config.line_space())
+            + config.top_margin()
+            + 10.0,
+    );
+    MessageLayout {
+        runs,
+        emotes,
+        line_count,
+        box_size,
+        box_position: Vec2::new(
+            box_size.x * -0.5,
+            config.message_box_vertical_offset + box_size.y,
+        ),
+        big_emote: false,
+    }
+}
+
+/// Layout of a message which is a single emote with any overlays, or None for other messages
+fn layout_big_emote(tokens: &[Token], config: &Config) -> Option<MessageLayout> {
+    let (first, overlays) = tokens.split_first()?;
+    let base = match first {
+        Token::Word(pieces) => match pieces.as_slice() {
+            [Piece::Emote(size)] => *size,
+            _ => return None,
+        },
+        // Nothing to draw over, so it is shown on its own
+        Token::Overlay(size) => *size,
+    };
+    let mut emotes = vec![EmoteSlot {
+        translation: Vec3::new(0.0, 50.0, 3.0),
+        scale: 0.45,
+    }];
+    for (index, overlay) in overlays.iter().enumerate() {
+        let Token::Overlay(size) = overlay else {
+            return None;
+        };
+        // Keep the size and stacking relative to the first emote
+        emotes.push(EmoteSlot {
+            translation: Vec3::new(0.0, 0.0, 0.1 * (index + 1) as f32),
+            scale: emote_scale(size, config) / emote_scale(&base, config),
+        });
+    }
+    Some(MessageLayout {
+        runs: vec![],
+        emotes,
+        line_count: 0,
+        box_size: Vec2::ZERO,
+        box_position: Vec2::ZERO,
+        big_emote: true,
+    })
+}
+
+/// Scale which makes an emote the configured size relative to the font
+fn emote_scale(size: &EmoteSize, config: &Config) -> f32 {
+    config.font_height() * config.emote_size_multiplier / size.height as f32
+}
+
+/// Non-breaking spaces (U+00A0) which leave room for an emote in the text
+fn emote_placeholder(size: &EmoteSize, config: &Config, measure: &impl TextWidth) -> String {
+    let emote_width = size.width as f32 * emote_scale(size, config);
+    let nbsp_width = measure.width(NBSP);
+    // Fonts without the character may measure it as nothing
+    let nbsp_width = if nbsp_width > 0.0 && nbsp_width.is_finite() {
+        nbsp_width
+    } else {
+        config.font_width()
+    };
+    let count = (emote_width / nbsp_width).ceil().max(1.0);
+    NBSP.repeat(count as usize)
+}
+
+/// Height of the top of a line relative to the top of the message box
+fn line_top(line_number: usize, config: &Config) -> f32 {
+    -(line_number as f32) * (config.font_height() + config.line_space())
+}
+
+/// Slot for an emote centred at `x` on a line
+fn emote_slot(x: f32, line_number: usize, size: &EmoteSize, config: &Config) -> EmoteSlot {
+    EmoteSlot {
+        translation: Vec3::new(
+            x,
+            line_top(line_number, config) - config.top_margin() - 0.5 * config.font_height(),
+            3.0,
+        ),
+        scale: emote_scale(size, config),
+    }
+}
+
+/// Message text split into lines which fit in the message box, measured as it is written
+struct Lines<'a, M: TextWidth> {
+    measure: &'a M,
+    max_width: f32,
+    lines: Vec<String>,
+    line: String,
+    line_number: usize,
+}
+
+impl<'a, M: TextWidth> Lines<'a, M> {
+    fn new(measure: &'a M, max_width: f32) -> Self {
+        Self {
+            measure,
+            max_width,
+            lines: vec![],
+            line: String::new(),
+            line_number: 0,
+        }
+    }
+
+    /// Width of the current line
+    fn width(&self) -> f32 {
+        self.measure.width(&self.line)
+    }
+
+    /// Start a new line if `text` does not fit on the current one
+    fn wrap_for(&mut self, text: &str) {
+        if !self.line.is_empty()
+            && self.measure.width(&format!("{}{}", self.line, text)) > self.max_width
+        {
+            self.end_line();
+        }
+    }
+
+    fn end_line(&mut self) {
+        debug!("Section: {:?} Length: {}", self.line, self.width());
+        self.lines.push(std::mem::take(&mut self.line));
+        self.line_number += 1;
+    }
+
+    fn push(&mut self, text: &str) {
+        self.line += text;
+    }
+
+    /// Add a word and the space after it. Words wider than a whole line are broken between
+    /// characters
+    fn push_word(&mut self, word: &str) {
+        let text = format!("{} ", word);
+        if self.measure.width(word) <= self.max_width {
+            self.wrap_for(&text);
+            self.push(&text);
+            return;
+        }
+        self.push_chars(word);
+        self.push(" ");
+    }
+
+    /// Add text, starting a new line before any character which does not fit
+    fn push_chars(&mut self, text: &str) {
+        for c in text.chars() {
+            let mut next = self.line.clone();
+            next.push(c);
+            if !self.line.is_empty() && self.measure.width(&next) > self.max_width {
+                self.end_line();
+            }
+            self.line.push(c);
+        }
+    }
+
+    /// Add the space for an emote, returning the x position of its centre
+    fn push_emote(&mut self, placeholder: &str) -> f32 {
+        let start = self.width();
+        self.push(placeholder);
+        (start + self.width()) / 2.0
+    }
+
+    /// The lines as text runs, the first `name_lines` of which are the sender's name
+    fn finish(mut self, name_lines: usize, config: &Config) -> Vec<TextRun> {
+        if !self.line.is_empty() {
+            self.end_line();
+        }
+        self.lines
+            .into_iter()
+            .enumerate()
+            .map(|(line_number, text)| TextRun {
+                text,
+                position: Vec2::new(0.0, line_top(line_number, config)),
+                is_name: line_number < name_lines,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every character, including non-breaking spaces, is 10 pixels wide
+    struct FixedAdvance;
+
+    impl TextWidth for FixedAdvance {
+        fn width(&self, text: &str) -> f32 {
+            text.chars().count() as f32 * 10.0
+        }
+    }
+
+    /// A font without non-breaking spaces, other characters are 10 pixels wide
+    struct NoNbsp;
+
+    impl TextWidth for NoNbsp {
+        fn width(&self, text: &str) -> f32 {
+            text.chars().filter(|c| *c != '\u{A0}').count() as f32 * 10.0
+        }
+    }
+
+    /// 20 pixel font, so emotes 28 pixels high are drawn at half size, in a box 10 characters wide
+    fn config() -> Config {
+        Config {
+            emote_size_multiplier: 1.0,
+            message_box_width: 100.0,
+            ..Config::for_tests()
+        }
+    }
+
+    const EMOTE: EmoteSize = EmoteSize {
+        width: 28,
+        height: 28,
+    };
+
+    fn text(word: &str) -> Token {
+        Token::Word(vec![Piece::Text(word.to_string())])
+    }
+
+    fn texts(layout: &MessageLayout) -> Vec<&str> {
+        layout.runs.iter().map(|run| run.text.as_str()).collect()
+    }
+
+    fn assert_fits(layout: &MessageLayout, config: &Config) {
+        for run in layout.runs.iter() {
+            assert!(
+                FixedAdvance.width(run.text.trim_end()) <= config.message_box_width,
+                "{:?} is wider than the message box",
+                run.text
+            );
+        }
+    }
+
+    /// Height of a line of text
+    fn line_height(config: &Config) -> f32 {
+        config.font_height() + config.line_space()
+    }
+
+    fn box_height(lines: usize, config: &Config) -> f32 {
+        lines as f32 * line_height(config) + config.top_margin() + 10.0
+    }
+
+    /// Height of the centre of an emote on a line
+    fn emote_y(line_number: usize, config: &Config) -> f32 {
+        let line_top = -(line_number as f32) * line_height(config);
+        line_top - config.top_margin() - 0.5 * config.font_height()
+    }
+
+    fn run(text: &str, line_number: usize, is_name: bool, config: &Config) -> TextRun {
+        TextRun {
+            text: text.to_string(),
+            position: Vec2::new(0.0, -(line_number as f32) * line_height(config)),
+            is_name,
+        }
+    }
+
+    #[test]
+    fn wraps_words_which_do_not_fit() {
+        let config = config();
+        let tokens = [text("hi"), text("there"), text("minawan")];
+        let layout = layout_message(&tokens, None, &FixedAdvance, &config);
+        assert_eq!(
+            layout.runs,
+            [
+                run("hi there ", 0, false, &config),
+                run("minawan ", 1, false, &config)
+            ]
+        );
+        assert!(layout.emotes.is_empty());
+        assert_eq!(layout.line_count, 2);
+        assert_eq!(layout.box_size, Vec2::new(100.0, box_height(2, &config)));
+        assert!(!layout.big_emote);
+    }
+
+    #[test]
+    fn shows_a_single_emote_large() {
+        let config = config();
+        let tokens = [Token::Word(vec![Piece::Emote(EMOTE)])];
+        let layout = layout_message(&tokens, Some("Minawan"), &FixedAdvance, &config);
+        assert!(layout.runs.is_empty());
+        assert_eq!(
+            layout.emotes,
+            [EmoteSlot {
+                translation: Vec3::new(0.0, 50.0, 3.0),
+                scale: 0.45,
+            }]
+        );
+        assert_eq!(layout.box_size, Vec2::ZERO);
+        assert!(layout.big_emote);
+    }
+
+    #[test]
+    fn stacks_overlays_on_a_large_emote() {
+        let config = config();
+        let tokens = [
+            Token::Word(vec![Piece::Emote(EMOTE)]),
+            Token::Overlay(EmoteSize {
+                width: 56,
+                height: 56,
+            }),
+            Token::Overlay(EmoteSize {
+                width: 28,
+                height: 14,
+            }),
+        ];
+        let layout = layout_message(&tokens, None, &FixedAdvance, &config);
+        assert!(layout.runs.is_empty());
+        // Overlays are scaled and stacked relative to the first emote
+        assert_eq!(
+            layout.emotes,
+            [
+                EmoteSlot {
+                    translation: Vec3::new(0.0, 50.0, 3.0),
+                    scale: 0.45,
+                },
+                EmoteSlot {
+                    translation: Vec3::new(0.0, 0.0, 0.1),
+                    scale: 0.5,
+                },
+                EmoteSlot {
+                    translation: Vec3::new(0.0, 0.0, 0.2),
+                    scale: 2.0,
+                },
+            ]
+        );
+        assert_eq!(layout.box_size, Vec2::ZERO);
+        assert!(layout.big_emote);
+    }
+
+    #[test]
+    fn shows_the_name_on_its_own_line() {
+        let config = config();
+        let layout = layout_message(&[text("hi")], Some("Minawan"), &FixedAdvance, &config);
+        assert_eq!(
+            layout.runs,
+            [
+                run("Minawan ", 0, true, &config),
+                run("hi ", 1, false, &config)
+            ]
+        );
+        assert_eq!(layout.line_count, 2);
+        assert_eq!(layout.box_size, Vec2::new(100.0, box_height(2, &config)));
+        assert!(!layout.big_emote);
+    }
+
+    #[test]
+    fn lays_out_a_three_line_message() {
+        let config = config();
+        let tokens = [
+            text("hey"),
+            Token::Word(vec![Piece::Emote(EMOTE)]),
+            Token::Overlay(EMOTE),
+            text("everyone"),
+            text("out"),
+            text("there"),
+        ];
+        let layout = layout_message(&tokens, None, &FixedAdvance, &config);
+        assert_eq!(
+            layout.runs,
+            [
+                run("hey \u{A0}\u{A0} ", 0, false, &config),
+                run("everyone ", 1, false, &config),
+                run("out there ", 2, false, &config),
+            ]
+        );
+        // The emote is centred on its two non-breaking spaces with the overlay drawn over it
+        assert_eq!(
+            layout.emotes,
+            [
+                EmoteSlot {
+                    translation: Vec3::new(50.0, emote_y(0, &config), 3.0),
+                    scale: 0.5,
+                },
+                EmoteSlot {
+                    translation: Vec3::new(50.0, emote_y(0, &config), 3.1),
+                    scale: 0.5,
+                },
+            ]
+        );
+        assert_eq!(layout.line_count, 3);
+        assert_eq!(layout.box_size, Vec2::new(100.0, box_height(3, &config)));
+        assert_eq!(
+            layout.box_position,
+            Vec2::new(
+                -50.0,
+                config.message_box_vertical_offset + box_height(3, &config)
+            )
+        );
+        assert!(!layout.big_emote);
+    }
+
+    #[test]
+    fn breaks_words_wider_than_a_line_between_characters() {
+        let config = config();
+        let tokens = [text("hi"), text("abcdefghijklmnopqrstuvw"), text("ok")];
+        let layout = layout_message(&tokens, None, &FixedAdvance, &config);
+        assert_eq!(texts(&layout), ["hi abcdefg", "hijklmnopq", "rstuvw ok "]);
+        assert_fits(&layout, &config);
+    }
+
+    #[test]
+    fn breaks_long_links_between_characters() {
+        let config = config();
+        let tokens = [text("https://example.com/?q=1")];
+        let layout = layout_message(&tokens, None, &FixedAdvance, &config);
+        assert_eq!(texts(&layout), ["https://ex", "ample.com/", "?q=1 "]);
+        assert_fits(&layout, &config);
+    }
+
+    #[test]
+    fn breaks_wide_words_with_emotes_between_characters() {
+        let config = config();
+        let tokens = [
+            text("hi"),
+            Token::Word(vec![
+                Piece::Text("abcdefghijkl".to_string()),
+                Piece::Emote(EMOTE),
+            ]),
+        ];
+        let layout = layout_message(&tokens, None, &FixedAdvance, &config);
+        // The emote takes two non-breaking spaces and stays on the line it was measured on
+        assert_eq!(texts(&layout), ["hi abcdefg", "hijkl\u{A0}\u{A0} "]);
+        assert_fits(&layout, &config);
+        assert_eq!(layout.emotes.len(), 1);
+        assert_eq!(layout.emotes[0].translation.x, 60.0);
+        assert_eq!(layout.emotes[0].translation.y, emote_y(1, &config));
+    }
+
+    #[test]
+    fn keeps_words_with_emotes_together_when_they_fit() {
+        let config = config();
+        let tokens = [
+            text("hello"),
+            Token::Word(vec![Piece::Text("hey".to_string()), Piece::Emote(EMOTE)]),
+        ];
+        let layout = layout_message(&tokens, None, &FixedAdvance, &config);
+        assert_eq!(texts(&layout), ["hello ", "hey\u{A0}\u{A0} "]);
+        assert_eq!(layout.emotes[0].translation.x, 40.0);
+    }
+
+    #[test]
+    fn sizes_emotes_by_the_font_without_non_breaking_spaces() {
+        let config = config();
+        // 14 pixel wide emote over 9.38 pixel characters
+        assert_eq!(emote_placeholder(&EMOTE, &config, &NoNbsp), "\u{A0}\u{A0}");
+
+        let tokens = [Token::Word(vec![
+            Piece::Text("hey".to_string()),
+            Piece::Emote(EMOTE),
+        ])];
+        let layout = layout_message(&tokens, None, &NoNbsp, &config);
+        assert_eq!(texts(&layout), ["hey\u{A0}\u{A0} "]);
+    }
+}
@@ -0,0 +1,638 @@
+use bevy::{
+    app::ScheduleRunnerPlugin,
+    prelude::*,
+    render::{
+        settings::{Backends, RenderCreation, WgpuSettings},
+        RenderPlugin,
+    },
+    time::TimeUpdateStrategy,
+    utils::{HashMap, HashSet},
+    window::{PresentMode, WindowFocused, WindowResized},
+};
+use bevy_web_asset::WebAssetPlugin;
+use chat::{ChatEvent, ChatSources};
+use clap::Parser;
+use emotes::{
+    apply_emote_set_change, cache::EmoteCache, emoji::EmojiImages, eviction::evict_emotes,
+    replace_channel_emotes, store_chat_emote, ChannelEmotes,
+};
+use env_logger::Env;
+use log::{error, info};
+use rand::{rngs::StdRng, SeedableRng};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use vleue_kinetoscope::{AnimatedImage, AnimatedImagePlugin};
+
+mod types;
+use types::*;
+pub use types::{MessageText, UserMarker};
+
+mod users;
+use users::{despawn_users, move_users, spawn_user, AVATAR_HEIGHT};
+
+mod messages;
+use messages::{
+    despawn_messages, display_message, relayout_messages, relayout_messages_on_font_load,
+};
+
+mod emotes;
+
+mod chat;
+
+mod config;
+use config::{load_config, Config};
+pub use config::{ConfigError, ConfigOverride};
+
+mod reload;
+use reload::{config_modified_time, reload_config};
+
+mod cli;
+use cli::Args;
+
+mod status;
+use status::update_status_indicator;
+
+mod layout;
+
+mod names;
+use names::{readable_name_color, update_name_tags};
+
+mod badges;
+use badges::{fallback_badge_images, BadgeImages};
+
+mod measure;
+
+/// Load the config and create the app. The chat sources are started on the current Tokio runtime
+pub fn build_app(config_path: String, overrides: Vec<ConfigOverride>) -> Result<App, ConfigError> {
+    let config = load_config(&config_path, &overrides)?;
+
+    // Create a channel to communicate between the chat sources and Bevy
+    let (tx, rx) = mpsc::channel::<ChatEvent>(100);
+
+    let emote_cache = EmoteCache::new(
+        &config.emote_cache_directory,
+        tokio::runtime::Handle::current(),
+    );
+
+    let mut chat_sources =
+        ChatSources::new(tx, tokio::runtime::Handle::current(), emote_cache.clone());
+    chat_sources.start(&config);
+
+    let config_watcher = ConfigWatcher {
+        modified: config_modified_time(&config_path),
+        path: config_path,
+        overrides,
+        timer: Timer::from_seconds(1.0, TimerMode::Repeating),
+    };
+
+    Ok(create_app(
+        config,
+        config_watcher,
+        ChatReceiver { receiver: rx },
+        chat_sources,
+        emote_cache,
+    ))
+}
+
+/// Run the app with the config and options from the command line. Must be called from within
+/// the Tokio runtime
+pub fn run() {
+    let env = Env::default()
+        .filter_or("LOG_LEVEL", "info")
+        .write_style_or("LOG_STYLE", "always");
+
+    env_logger::init_from_env(env);
+
+    let args = Args::parse();
+    let overrides = args.overrides();
+
+    match build_app(args.config, overrides) {
+        // Run Bevy application
+        Ok(mut app) => {
+            app.run();
+        }
+        Err(err) => {
+            error!("{}", err);
+            show_error(&err.to_string());
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Time between frames in headless mode
+const HEADLESS_FRAME_TIME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+/// Create the Bevy app. In headless mode no window is created and only the minimal plugins
+/// needed to run the simulation are added
+fn create_app(
+    config: Config,
+    config_watcher: ConfigWatcher,
+    chat_receiver: ChatReceiver,
+    chat_sources: ChatSources,
+    emote_cache: EmoteCache,
+) -> App {
+    let headless = config.headless;
+    let emojis = EmojiImages::new(&config.emoji_directory);
+    let badge_images = BadgeImages::new(config.badge_url.clone(), &config.badge_directory);
+    let rng = match config.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    let mut app = App::new();
+    app.insert_resource(config)
+        .insert_resource(config_watcher)
+        .insert_resource(chat_receiver)
+        .insert_resource(chat_sources)
+        .insert_resource(SeededRng(rng))
+        .insert_resource(ScreenRect(Rect::new(0.0, 0.0, 1920.0, 1080.0)))
+        .insert_resource(badge_images)
+        .insert_resource(EmoteStorage {
+            all: HashMap::new(),
+            chat: HashMap::new(),
+            loaded: HashMap::new(),
+            cache: emote_cache,
+            emojis,
+            uses: 0,
+        })
+        .insert_resource(AppState {
+            active_users: HashMap::new(),
+            program_state: ProgramState::Loading,
+            channel_id: None,
+            emote_status: None,
+        });
+
+    if headless {
+        app.add_plugins((
+            MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(HEADLESS_FRAME_TIME)),
+            AssetPlugin::default(),
+            TransformPlugin,
+            HierarchyPlugin,
+        ))
+        // Every frame advances the clock by the same amount so runs can be reproduced
+        .insert_resource(TimeUpdateStrategy::ManualDuration(HEADLESS_FRAME_TIME))
+        .init_asset::<Image>()
+        .init_asset::<Font>()
+        .init_asset::<AnimatedImage>();
+    } else {
+        // Set up Wgpu settings
+        let wgpu_settings = WgpuSettings {
+            backends: Some(Backends::VULKAN),
+            ..Default::default()
+        };
+
+        app.insert_resource(ClearColor(Color::NONE))
+            .add_plugins(WebAssetPlugin)
+            .add_plugins(
+                DefaultPlugins
+                    .set(WindowPlugin {
+                        primary_window: Some(Window {
+                            title: "Transparent Window".to_string(),
+                            transparent: true,
+                            decorations: false,
+                            present_mode: PresentMode::Mailbox,
+                            window_level: bevy::window::WindowLevel::AlwaysOnTop,
+                            ..default()
+                        }),
+                        ..default()
+                    })
+                    .set(RenderPlugin {
+                        render_creation: RenderCreation::Automatic(wgpu_settings),
+                        synchronous_pipeline_compilation: false,
+                    }),
+            )
+            .add_plugins(AnimatedImagePlugin)
+            .add_systems(Startup, setup_window)
+            .add_systems(
+                Update,
+                (
+                    update_screen_rect.before(handle_chat_messages),
+                    // Runs first so an avatar raised for a name tag still being spawned is not
+                    // moved back down
+                    handle_window_events.before(update_name_tags),
+                ),
+            );
+    }
+
+    app.add_systems(Startup, setup).add_systems(
+        Update,
+        (
+            move_users,
+            despawn_users,
+            despawn_messages,
+            handle_chat_messages,
+            relayout_messages_on_font_load,
+            adjust_sprite_scale_system,
+            reload_config,
+            update_status_indicator,
+            update_name_tags,
+            fallback_badge_images,
+            evict_emotes,
+        ),
+    );
+    app
+}
+
+/// Show an error to the user. The release build has no console so a message box is used
+#[cfg(windows)]
+fn show_error(message: &str) {
+    use windows::{
+        core::HSTRING,
+        Win32::{
+            Foundation::HWND,
+            UI::WindowsAndMessaging::{MessageBoxW, MB_ICONERROR, MB_OK},
+        },
+    };
+
+    unsafe {
+        MessageBoxW(
+            HWND::default(),
+            &HSTRING::from(message),
+            &HSTRING::from("Minawan Watch Party"),
+            MB_OK | MB_ICONERROR,
+        );
+    }
+}
+
+/// Show an error to the user
+#[cfg(not(windows))]
+fn show_error(message: &str) {
+    eprintln!("{}", message);
+}
+
+// Set up the camera and window
+fn setup_window(mut commands: Commands, mut windows: Query<&mut Window>, config: Res<Config>) {
+    commands.spawn(Camera2dBundle::default());
+    let mut window: Mut<'_, Window> = windows.single_mut();
+    window
+        .resolution
+        .set_scale_factor_override(Some(config.scale));
+    window.cursor.hit_test = false;
+    window.set_maximized(true);
+}
+
+// Start loading the emotes and start the program
+fn setup(
+    mut app_state: ResMut<AppState>,
+    mut chat_sources: ResMut<ChatSources>,
+    config: Res<Config>,
+) {
+    // Without a configured id the emotes are loaded once the channel has been joined
+    if let Some(channel_id) = config.channel_id.clone() {
+        load_channel_emotes(&mut app_state, &mut chat_sources, channel_id, &config);
+    }
+
+    app_state.program_state = ProgramState::Running;
+}
+
+/// Start loading the emotes for a channel in the background unless they have already been
+/// loaded. Messages are shown without the channel emotes until they arrive
+fn load_channel_emotes(
+    app_state: &mut AppState,
+    chat_sources: &mut ChatSources,
+    channel_id: String,
+    config: &Config,
+) {
+    if app_state.channel_id.as_ref() == Some(&channel_id) {
+        return;
+    }
+    info!("Loading the emotes for channel {}", channel_id);
+    chat_sources.load_channel_emotes(channel_id.clone(), config.emote_providers.clone());
+    app_state.channel_id = Some(channel_id);
+}
+
+/// Store loaded channel emotes in place of the previous ones and watch the 7TV emote set. If 7TV
+/// could not be reached the emotes are loaded again in the background and the problem is shown on
+/// screen
+fn apply_channel_emotes(
+    emotes_rec: &mut ResMut<EmoteStorage>,
+    app_state: &mut AppState,
+    chat_sources: &mut ChatSources,
+    channel_emotes: ChannelEmotes,
+    config: &Config,
+) {
+    replace_channel_emotes(emotes_rec, channel_emotes.emotes);
+    match channel_emotes.seventv_emote_set {
+        Some(emote_set_id) => {
+            chat_sources.watch_emote_set(config.seventv_events_url.clone(), emote_set_id)
+        }
+        None => chat_sources.stop_watching_emote_set(),
+    }
+    match channel_emotes.seventv_error {
+        Some(_) => {
+            app_state.emote_status = Some("7TV emotes unavailable, retrying".to_string());
+            chat_sources.retry_channel_emotes(
+                channel_emotes.channel_id.clone(),
+                config.emote_providers.clone(),
+            );
+        }
+        None => {
+            app_state.emote_status = None;
+            chat_sources.stop_loading_channel_emotes();
+        }
+    }
+}
+
+/// System to handle incoming chat messages
+#[allow(clippy::too_many_arguments)]
+fn handle_chat_messages(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    fonts: Res<Assets<Font>>,
+    mut emote_rec: ResMut<EmoteStorage>,
+    mut app_state: ResMut<AppState>,
+    mut rng: ResMut<SeededRng>,
+    config: Res<Config>,
+    screen_rect: Res<ScreenRect>,
+    time: Res<Time>,
+    mut chat_receiver: ResMut<ChatReceiver>,
+    mut chat_sources: ResMut<ChatSources>,
+    message_query: Query<(Entity, &MessageText, &MessageSpawnTime, &Parent)>,
+) {
+    // Ids of chat emotes whose size became known, shown as text in messages already on screen
+    let mut resolved_emotes: HashSet<String> = HashSet::new();
+    // Channel emotes arrived, which may be used in messages already on screen
+    let mut channel_emotes_loaded = false;
+    // Messages shown this frame, which are not in the query yet
+    let mut shown_messages: Vec<(Entity, Option<String>)> = vec![];
+    // Messages and avatars removed by moderators
+    let mut removed: HashSet<Entity> = HashSet::new();
+    while let Ok(event) = chat_receiver.receiver.try_recv() {
+        let chat_message = match event {
+            ChatEvent::Message(chat_message) => chat_message,
+            ChatEvent::ChannelId(channel_id) => {
+                // An id set in the config takes priority
+                if config.channel_id.is_none() {
+                    load_channel_emotes(&mut app_state, &mut chat_sources, channel_id, &config);
+                }
+                continue;
+            }
+            ChatEvent::EmoteSetChange(change) => {
+                apply_emote_set_change(&mut emote_rec, change);
+                continue;
+            }
+            ChatEvent::ChannelEmotes(channel_emotes) => {
+                // Emotes for a channel which is no longer shown are dropped
+                if app_state.channel_id.as_ref() == Some(&channel_emotes.channel_id) {
+                    info!(
+                        "Loaded the emotes for channel {}",
+                        channel_emotes.channel_id
+                    );
+                    apply_channel_emotes(
+                        &mut emote_rec,
+                        &mut app_state,
+                        &mut chat_sources,
+                        channel_emotes,
+                        &config,
+                    );
+                    channel_emotes_loaded = true;
+                }
+                continue;
+            }
+            ChatEvent::EmoteMeta(emote) => {
+                let id = emote._id.clone();
+                if store_chat_emote(&mut emote_rec, emote) {
+                    resolved_emotes.insert(id);
+                }
+                continue;
+            }
+            ChatEvent::MessageDeleted(message_id) => {
+                let messages = message_query
+                    .iter()
+                    .map(|(entity, message_text, _, _)| (entity, message_text.id.as_ref()))
+                    .chain(
+                        shown_messages
+                            .iter()
+                            .map(|(entity, id)| (*entity, id.as_ref())),
+                    );
+                for (entity, id) in messages {
+                    if id == Some(&message_id) && removed.insert(entity) {
+                        commands.entity(entity).despawn_recursive();
+                    }
+                }
+                continue;
+            }
+            ChatEvent::UserRemoved { platform, user_id } => {
+                if let Some(user) = app_state.active_users.remove(&(platform, user_id)) {
+                    info!("Removing user: {}", user._name);
+                    commands.entity(user.entity).despawn_recursive();
+                    removed.insert(user.entity);
+                    // Their messages go with the avatar
+                    for (entity, _, _, parent) in message_query.iter() {
+                        if parent.get() == user.entity {
+                            removed.insert(entity);
+                        }
+                    }
+                }
+                continue;
+            }
+            ChatEvent::ChatCleared => {
+                let messages = message_query
+                    .iter()
+                    .map(|(entity, _, _, _)| entity)
+                    .chain(shown_messages.iter().map(|(entity, _)| *entity));
+                for entity in messages {
+                    if removed.insert(entity) {
+                        commands.entity(entity).despawn_recursive();
+                    }
+                }
+                continue;
+            }
+        };
+        // Add any new emotes to the storage
+        for span in chat_message.emotes.iter() {
+            if store_chat_emote(&mut emote_rec, span.emote.clone()) {
+                resolved_emotes.insert(span.emote._id.clone());
+            }
+        }
+        let message_id = chat_message.id.clone();
+        let message_text = MessageText {
+            id: chat_message.id.clone(),
+            sender_color: readable_name_color(chat_message.name_color, &chat_message.user),
+            sender: chat_message.user.clone(),
+            text: chat_message.message.clone(),
+            emotes: chat_message
+                .emotes
+                .iter()
+                .map(|span| (span.char_range.clone(), span.emote._id.clone()))
+                .collect(),
+        };
+        let user_key = (chat_message.platform, chat_message.user_id.clone());
+        // Check if the user already exists
+        if let Some(user) = app_state.active_users.get_mut(&user_key) {
+            // Update the user's last message time and display the message
+            let message_entity = display_message(
+                &mut commands,
+                &asset_server,
+                &fonts,
+                &mut emote_rec,
+                &config,
+                user.entity,
+                message_text,
+                time.elapsed(),
+            );
+            shown_messages.push((message_entity, message_id));
+            // user.last_message = Some(message);
+            user.last_message_time = time.elapsed();
+        } else {
+            // Add new user and spawn their avatar
+            let entity = spawn_user(
+                &mut commands,
+                &asset_server,
+                &chat_message,
+                &config,
+                &mut rng,
+                screen_rect.0,
+                time.elapsed(),
+            );
+            let message_entity = display_message(
+                &mut commands,
+                &asset_server,
+                &fonts,
+                &mut emote_rec,
+                &config,
+                entity,
+                message_text,
+                time.elapsed(),
+            );
+            shown_messages.push((message_entity, message_id));
+            app_state.active_users.insert(
+                user_key,
+                User {
+                    entity,
+                    _name: chat_message.user.clone(),
+                    last_message_time: time.elapsed(),
+                },
+            );
+        }
+    }
+
+    if channel_emotes_loaded || !resolved_emotes.is_empty() {
+        relayout_messages(
+            &mut commands,
+            &asset_server,
+            &fonts,
+            &mut emote_rec,
+            &config,
+            &message_query,
+            |entity, message| {
+                !removed.contains(&entity)
+                    && (channel_emotes_loaded
+                        || message
+                            .emotes
+                            .iter()
+                            .any(|(_, id)| resolved_emotes.contains(id)))
+            },
+        );
+    }
+}
+
+/// Keep the screen rect in sync with the camera viewport
+fn update_screen_rect(camera_query: Query<&Camera>, mut screen_rect: ResMut<ScreenRect>) {
+    if let Some(rect) = camera_query
+        .get_single()
+        .ok()
+        .and_then(|camera| camera.logical_viewport_rect())
+    {
+        screen_rect.0 = rect;
+    }
+}
+
+fn handle_window_events(
+    window_moved_events: EventReader<WindowMoved>,
+    window_resized_events: EventReader<WindowResized>,
+    window_focused_events: EventReader<WindowFocused>,
+    windows: Query<&mut Window>,
+    mut avatar_query: Query<(&mut Transform, Option<&Children>), With<UserMarker>>,
+    tag_query: Query<&NameTag>,
+) {
+    // Check if any relevant window events have occurred
+    if !window_moved_events.is_empty()
+        || !window_resized_events.is_empty()
+        || !window_focused_events.is_empty()
+    {
+        // Get the primary window
+        if let Ok(window) = windows.get_single() {
+            let rect = window.physical_size();
+            for (mut transform, children) in avatar_query.iter_mut() {
+                transform.translation.x = transform
+                    .translation
+                    .x
+                    .max(-(rect.x as f32 / 2.0))
+                    .min(rect.x as f32 / 2.0);
+                // Keep avatars raised above their name tags
+                let tag_height: f32 = children
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|child| tag_query.get(*child).ok())
+                    .map(|tag| tag.height)
+                    .sum();
+                transform.translation.y = -(rect.y as f32 / 2.0) + 25.0 + tag_height;
+            }
+        }
+    }
+}
+
+fn adjust_sprite_scale_system(
+    mut commands: Commands,
+    mut query: Query<(Entity, &Handle<Image>, &mut Sprite, &mut Visibility), With<AdjustScale>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    for (entity, texture_handle, mut sprite, mut visibility) in query.iter_mut() {
+        if let Some(image) = images.get_mut(texture_handle) {
+            let texture_height = image.texture_descriptor.size.height as f32;
+            let scale_factor = AVATAR_HEIGHT / texture_height;
+
+            // Modify sprite custom size and make visible
+            sprite.custom_size.replace(Vec2::new(
+                image.texture_descriptor.size.width as f32 * scale_factor,
+                texture_height * scale_factor,
+            ));
+            *visibility = Visibility::Visible;
+
+            // Remove the marker component
+            commands.entity(entity).remove::<AdjustScale>();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_events_keep_avatars_above_their_name_tags() {
+        let mut app = App::new();
+        app.add_event::<WindowMoved>()
+            .add_event::<WindowResized>()
+            .add_event::<WindowFocused>()
+            .add_systems(Update, handle_window_events);
+        let window = app.world_mut().spawn(Window::default()).id();
+        let tagged = app
+            .world_mut()
+            .spawn((UserMarker {}, Transform::from_xyz(0.0, 100.0, 0.0)))
+            .with_children(|builder| {
+                builder.spawn(NameTag {
+                    height: 12.0,
+                    badges: false,
+                    font_loaded: true,
+                });
+            })
+            .id();
+        let untagged = app
+            .world_mut()
+            .spawn((UserMarker {}, Transform::from_xyz(0.0, 100.0, 0.0)))
+            .id();
+
+        app.world_mut().send_event(WindowFocused {
+            window,
+            focused: true,
+        });
+        app.update();
+
+        // The default window is 720 pixels high
+        let y = |app: &App, entity| app.world().get::<Transform>(entity).unwrap().translation.y;
+        assert_eq!(y(&app, tagged), -360.0 + 25.0 + 12.0);
+        assert_eq!(y(&app, untagged), -360.0 + 25.0);
+    }
+}
@@ -0,0 +1,48 @@
+use ab_glyph::{Font as _, FontArc, PxScale, ScaleFont};
+use bevy::{
+    asset::{Assets, Handle},
+    text::Font,
+};
+
+use crate::{config::Config, layout::TextWidth};
+
+/// Measures text with the glyph advances of the message font, so any TTF or OTF font can be
+/// used. Until the font has loaded widths are estimated from the font size
+pub(crate) struct TextMeasure<'a> {
+    font: Option<&'a FontArc>,
+    font_size: f32,
+    /// Width of every character while the font is loading
+    fallback_width: f32,
+}
+
+impl<'a> TextMeasure<'a> {
+    pub(crate) fn new(fonts: &'a Assets<Font>, font: &Handle<Font>, config: &Config) -> Self {
+        Self {
+            font: fonts.get(font).map(|font| &font.font),
+            font_size: config.font_size,
+            fallback_width: config.font_width(),
+        }
+    }
+}
+
+impl TextWidth for TextMeasure<'_> {
+    /// Width of a run of text, including the kerning between its characters
+    fn width(&self, text: &str) -> f32 {
+        let Some(font) = self.font else {
+            return text.chars().count() as f32 * self.fallback_width;
+        };
+        // Bevy lays out text with the font size as the pixel scale
+        let font = font.as_scaled(PxScale::from(self.font_size));
+        let mut width = 0.0;
+        let mut previous = None;
+        for c in text.chars() {
+            let glyph = font.glyph_id(c);
+            if let Some(previous) = previous {
+                width += font.kern(previous, glyph);
+            }
+            width += font.h_advance(glyph);
+            previous = Some(glyph);
+        }
+        width
+    }
+}
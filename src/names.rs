@@ -0,0 +1,173 @@
+use bevy::{
+    asset::{AssetServer, Assets},
+    color::{Color, Oklcha, Srgba},
+    hierarchy::{BuildChildren, Children, DespawnRecursiveExt},
+    math::{Vec2, Vec3},
+    prelude::{default, Commands, Entity, Query, Res, ResMut, SpatialBundle, Transform, With},
+    sprite::{Anchor, Sprite, SpriteBundle},
+    text::{Font, Text, Text2dBundle, TextStyle},
+};
+
+use crate::{
+    badges::BadgeImages, config::Config, layout::TextWidth, measure::TextMeasure,
+    users::AVATAR_HEIGHT, BadgeIcon, NameTag, UserDetails, UserMarker,
+};
+
+/// Colors Twitch gives users who have not chosen one
+const DEFAULT_NAME_COLORS: [&str; 15] = [
+    "#FF0000", "#0000FF", "#008000", "#B22222", "#FF7F50", "#9ACD32", "#FF4500", "#2E8B57",
+    "#DAA520", "#D2691E", "#5F9EA0", "#1E90FF", "#FF69B4", "#8A2BE2", "#00FF7F",
+];
+
+/// Perceptual lightness names are raised to so dark colors can be read on dark backgrounds
+const MIN_NAME_LIGHTNESS: f32 = 0.65;
+
+/// Color to draw a user's name in. Users without a chat color get one of the Twitch defaults
+/// picked from their name, and dark colors are lightened
+pub(crate) fn readable_name_color(color: Option<Color>, name: &str) -> Color {
+    let color = color.unwrap_or_else(|| {
+        let index = name.bytes().map(usize::from).sum::<usize>() % DEFAULT_NAME_COLORS.len();
+        Srgba::hex(DEFAULT_NAME_COLORS[index])
+            .expect("Default name colors are valid")
+            .into()
+    });
+    let mut lightened = Oklcha::from(color);
+    if lightened.lightness >= MIN_NAME_LIGHTNESS {
+        return color;
+    }
+    lightened.lightness = MIN_NAME_LIGHTNESS;
+    lightened.into()
+}
+
+/// Font size of names relative to messages
+const NAME_TAG_SCALE: f32 = 0.6;
+
+/// Font size of the name shown under avatars, which is also the space left for it and the size
+/// of badges
+fn name_tag_size(config: &Config) -> f32 {
+    config.font_size * NAME_TAG_SCALE
+}
+
+/// System to show each user's name and badges under their avatar when enabled in the config.
+/// Avatars are raised to make room for the name and lowered again when it is removed. Names
+/// shown before the message font loaded are shown again once it has, to centre them properly
+pub(crate) fn update_name_tags(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    fonts: Res<Assets<Font>>,
+    config: Res<Config>,
+    mut badge_images: ResMut<BadgeImages>,
+    mut user_query: Query<
+        (Entity, &UserDetails, &mut Transform, Option<&Children>),
+        With<UserMarker>,
+    >,
+    tag_query: Query<&NameTag>,
+) {
+    let size = name_tag_size(&config);
+    let font_loaded = fonts.contains(&asset_server.load::<Font>(&config.font_url));
+    for (entity, details, mut transform, children) in user_query.iter_mut() {
+        let tag = children
+            .into_iter()
+            .flatten()
+            .find_map(|child| tag_query.get(*child).ok().map(|tag| (*child, tag)));
+
+        match tag {
+            // Tags made with different settings are replaced
+            Some((tag_entity, tag))
+                if !config.show_names
+                    || tag.height != size
+                    || tag.badges != config.show_badges
+                    || tag.font_loaded != font_loaded =>
+            {
+                commands.entity(tag_entity).despawn_recursive();
+                transform.translation.y -= tag.height;
+            }
+            None if config.show_names => {
+                transform.translation.y += size;
+                let tag = spawn_name_tag(
+                    &mut commands,
+                    &asset_server,
+                    &fonts,
+                    &config,
+                    &mut badge_images,
+                    details,
+                );
+                commands.entity(tag).set_parent(entity);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Spawn a user's badges and name centred under their avatar
+fn spawn_name_tag(
+    commands: &mut Commands,
+    asset_server: &AssetServer,
+    fonts: &Assets<Font>,
+    config: &Config,
+    badge_images: &mut BadgeImages,
+    details: &UserDetails,
+) -> Entity {
+    let size = name_tag_size(config);
+    let gap = size * 0.25;
+    let font = asset_server.load(&config.font_url);
+    let badges: Vec<(String, Option<String>)> = match config.show_badges {
+        true => details
+            .badges
+            .iter()
+            .filter_map(|badge| badge_images.source(badge))
+            .collect(),
+        false => vec![],
+    };
+    // Text widths scale with the font size
+    let name_width = TextMeasure::new(fonts, &font, config).width(&details.name) * NAME_TAG_SCALE;
+    let width = badges.len() as f32 * (size + gap) + name_width;
+
+    commands
+        .spawn((
+            SpatialBundle::from_transform(Transform::from_translation(Vec3::new(
+                0.0,
+                AVATAR_HEIGHT * -0.5,
+                1.0,
+            ))),
+            NameTag {
+                height: size,
+                badges: config.show_badges,
+                font_loaded: fonts.contains(&font),
+            },
+        ))
+        .with_children(|builder| {
+            let mut x = width * -0.5;
+            for (path, fallback) in badges {
+                builder.spawn((
+                    SpriteBundle {
+                        texture: asset_server.load(path),
+                        sprite: Sprite {
+                            custom_size: Some(Vec2::splat(size)),
+                            anchor: Anchor::TopLeft,
+                            ..default()
+                        },
+                        transform: Transform::from_translation(Vec3::new(x, 0.0, 0.0)),
+                        ..default()
+                    },
+                    BadgeIcon { fallback },
+                ));
+                x += size + gap;
+            }
+            builder.spawn(Text2dBundle {
+                text: Text::from_section(
+                    details.name.clone(),
+                    TextStyle {
+                        font,
+                        font_size: size,
+                        color: details.color,
+                    },
+                )
+                .with_no_wrap(),
+                text_anchor: Anchor::TopLeft,
+                transform: Transform::from_translation(Vec3::new(x, 0.0, 0.0)),
+                ..default()
+            });
+        })
+        .id()
+}
@@ -0,0 +1,126 @@
+use std::time::SystemTime;
+
+use bevy::{
+    asset::{AssetServer, Assets},
+    hierarchy::Parent,
+    prelude::{Commands, Entity, Query, Res, ResMut},
+    text::Font,
+    time::Time,
+    utils::HashMap,
+    window::Window,
+};
+use log::{info, warn};
+
+use crate::{
+    chat::ChatSources,
+    config::{load_config, Config},
+    emotes::replace_channel_emotes,
+    load_channel_emotes,
+    messages::relayout_messages,
+    AppState, ConfigWatcher, EmoteStorage, MessageSpawnTime, MessageText,
+};
+
+/// Last time the file at `path` was modified, if it can be read
+pub(crate) fn config_modified_time(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// System to apply changes to config.ini while running. Avatars stay on screen, visible
+/// messages are laid out again and the chat sources reconnect if the channel changed
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn reload_config(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    fonts: Res<Assets<Font>>,
+    mut emote_rec: ResMut<EmoteStorage>,
+    mut app_state: ResMut<AppState>,
+    mut config: ResMut<Config>,
+    mut config_watcher: ResMut<ConfigWatcher>,
+    mut chat_sources: ResMut<ChatSources>,
+    mut windows: Query<&mut Window>,
+    message_query: Query<(Entity, &MessageText, &MessageSpawnTime, &Parent)>,
+    time: Res<Time>,
+) {
+    if !config_watcher.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let modified = config_modified_time(&config_watcher.path);
+    if modified == config_watcher.modified {
+        return;
+    }
+    config_watcher.modified = modified;
+
+    let new_config = match load_config(&config_watcher.path, &config_watcher.overrides) {
+        Ok(new_config) => new_config,
+        Err(err) => {
+            warn!("Keeping the current config. {}", err);
+            return;
+        }
+    };
+    info!("Reloading {}", config_watcher.path);
+
+    if new_config.channel_name != config.channel_name
+        || new_config.discord != config.discord
+        || new_config.replay != config.replay
+        || new_config.recording != config.recording
+    {
+        info!("Chat settings changed, reconnecting");
+        chat_sources.start(&new_config);
+    }
+    if new_config.channel_name != config.channel_name || new_config.replay != config.replay {
+        // The emotes of the last channel are loaded again for the new one once it is joined
+        info!("Channel changed, dropping the channel emotes");
+        replace_channel_emotes(&mut emote_rec, HashMap::new());
+        app_state.channel_id = None;
+        app_state.emote_status = None;
+        chat_sources.stop_watching_emote_set();
+        chat_sources.stop_loading_channel_emotes();
+    }
+    if new_config.emote_providers != config.emote_providers
+        || new_config.seventv_events_url != config.seventv_events_url
+    {
+        // Load the emotes again for the last channel with the new emote settings
+        if let Some(channel_id) = app_state.channel_id.take() {
+            let channel_id = new_config.channel_id.clone().unwrap_or(channel_id);
+            load_channel_emotes(&mut app_state, &mut chat_sources, channel_id, &new_config);
+        }
+    }
+    if let Some(channel_id) = new_config.channel_id.clone() {
+        load_channel_emotes(&mut app_state, &mut chat_sources, channel_id, &new_config);
+    }
+    if new_config.headless != config.headless || new_config.seed != config.seed {
+        warn!("HEADLESS and SEED only take effect after a restart");
+    }
+    if new_config.emote_cache_directory != config.emote_cache_directory {
+        warn!("CACHE_DIRECTORY only takes effect after a restart");
+    }
+    if new_config.emoji_directory != config.emoji_directory {
+        warn!("EMOJI_DIRECTORY only takes effect after a restart");
+    }
+    if new_config.badge_url != config.badge_url
+        || new_config.badge_directory != config.badge_directory
+    {
+        warn!("BADGE_URL and BADGE_DIRECTORY only take effect after a restart");
+    }
+
+    for mut window in windows.iter_mut() {
+        window
+            .resolution
+            .set_scale_factor_override(Some(new_config.scale));
+    }
+
+    *config = new_config;
+
+    // Lay out the visible messages again with the new sizes
+    relayout_messages(
+        &mut commands,
+        &asset_server,
+        &fonts,
+        &mut emote_rec,
+        &config,
+        &message_query,
+        |_, _| true,
+    );
+}
@@ -0,0 +1,47 @@
+use bevy::{
+    asset::AssetServer,
+    color::Color,
+    prelude::{Commands, DespawnRecursiveExt, Entity, Query, Res, With},
+    text::{Text, TextStyle},
+    ui::{node_bundles::TextBundle, PositionType, Style, Val},
+};
+
+use crate::{config::Config, AppState, StatusIndicator};
+
+/// System to show problems such as 7TV being unreachable in the corner of the screen
+pub(crate) fn update_status_indicator(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    app_state: Res<AppState>,
+    config: Res<Config>,
+    mut indicator_query: Query<(Entity, &mut Text), With<StatusIndicator>>,
+) {
+    match (&app_state.emote_status, indicator_query.get_single_mut()) {
+        (Some(status), Ok((_, mut text))) => {
+            if text.sections[0].value != *status {
+                text.sections[0].value = status.clone();
+            }
+        }
+        (Some(status), Err(_)) => {
+            commands.spawn((
+                TextBundle::from_section(
+                    status.clone(),
+                    TextStyle {
+                        font: asset_server.load(&config.font_url),
+                        font_size: config.font_size * 0.75,
+                        color: Color::srgb(1.0, 0.6, 0.2),
+                    },
+                )
+                .with_style(Style {
+                    position_type: PositionType::Absolute,
+                    top: Val::Px(5.0),
+                    left: Val::Px(5.0),
+                    ..Default::default()
+                }),
+                StatusIndicator {},
+            ));
+        }
+        (None, Ok((entity, _))) => commands.entity(entity).despawn_recursive(),
+        (None, Err(_)) => {}
+    }
+}
@@ -1,41 +1,109 @@
-use std::time::Instant;
+use std::{
+    ops::Range,
+    time::{Duration, SystemTime},
+};
 
 use bevy::{
-    prelude::{Bundle, Component, Entity, Resource}, sprite::SpriteBundle, utils::HashMap
+    color::Color,
+    math::Rect,
+    prelude::{Bundle, Component, Entity, Resource},
+    sprite::SpriteBundle,
+    time::Timer,
+    utils::HashMap,
 };
+use rand::rngs::StdRng;
 use tokio::sync::mpsc;
 
-use crate::emotes::emote_types::{Emote, EmoteHandles};
+use crate::{
+    chat::{ChatBadge, ChatEvent, Platform},
+    config::ConfigOverride,
+    emotes::{
+        cache::EmoteCache,
+        emoji::EmojiImages,
+        emote_types::{Emote, EmoteHandles},
+    },
+};
 
 /// Marker component to identify avatars that need their scale adjusted
 #[derive(Component, Default)]
 pub(crate) struct AdjustScale {}
 
-/// Twitch message struct
-pub(crate) struct TwitchMessage {
-    pub(crate) user: String,
-    pub(crate) message: String,
-    pub(crate) emotes: Vec<Emote>,
+// Wrap the mpsc::Receiver in a struct and derive Resource
+#[derive(Resource)]
+pub(crate) struct ChatReceiver {
+    pub(crate) receiver: mpsc::Receiver<ChatEvent>,
 }
 
-// Wrap the mpsc::Receiver in a struct and derive Resource
+/// Tracks config.ini so changes can be applied while running
 #[derive(Resource)]
-pub(crate) struct TwitchReceiver {
-    pub(crate) receiver: mpsc::Receiver<TwitchMessage>,
+pub(crate) struct ConfigWatcher {
+    pub(crate) path: String,
+    /// Command line overrides applied on every reload
+    pub(crate) overrides: Vec<ConfigOverride>,
+    pub(crate) modified: Option<SystemTime>,
+    pub(crate) timer: Timer,
 }
 
+/// Random number generator used by all systems so runs can be reproduced with a fixed seed
+#[derive(Resource)]
+pub(crate) struct SeededRng(pub(crate) StdRng);
+
+/// Logical rect of the screen avatars walk around in
+#[derive(Resource)]
+pub(crate) struct ScreenRect(pub(crate) Rect);
+
 /// Struct to store all emotes that have not been loaded yet
 #[derive(Resource)]
 pub(crate) struct EmoteStorage {
+    /// Emote provider emotes by name, shown wherever their name is used
     pub(crate) all: HashMap<String, Emote>,
+    /// Emotes sent with chat messages by id, only shown where a message marks them
+    pub(crate) chat: HashMap<String, Emote>,
+    /// Images of emotes which have been shown, by emote url so emotes with the same name from
+    /// different places do not share an image
     pub(crate) loaded: HashMap<String, EmoteHandles>,
+    pub(crate) cache: EmoteCache,
+    pub(crate) emojis: EmojiImages,
+    /// Number of times a loaded emote has been shown, used to find the least recently used
+    pub(crate) uses: u64,
+}
+
+impl EmoteStorage {
+    /// Count a use of a loaded emote, returning the value to store in its `last_used`
+    pub(crate) fn next_use(&mut self) -> u64 {
+        self.uses += 1;
+        self.uses
+    }
+}
+
+#[cfg(test)]
+impl EmoteStorage {
+    /// Storage without any emotes, with cache and emoji directories named after the test. Must be
+    /// created inside a Tokio runtime
+    pub(crate) fn for_tests(name: &str) -> Self {
+        Self {
+            all: HashMap::new(),
+            chat: HashMap::new(),
+            loaded: HashMap::new(),
+            cache: EmoteCache::new(
+                &format!("{}-test-cache", name),
+                tokio::runtime::Handle::current(),
+            ),
+            emojis: EmojiImages::new(&format!("{}-test-emoji", name)),
+            uses: 0,
+        }
+    }
 }
 
 /// App State struct stored as a Resource
 #[derive(Resource)]
 pub(crate) struct AppState {
-    pub(crate) active_users: HashMap<String, User>,
+    pub(crate) active_users: HashMap<(Platform, String), User>,
     pub(crate) program_state: ProgramState,
+    /// Twitch channel id the 7TV channel emotes were loaded for
+    pub(crate) channel_id: Option<String>,
+    /// Problem loading the emotes, shown on screen until it is resolved
+    pub(crate) emote_status: Option<String>,
 }
 
 #[derive(Resource, Debug)]
@@ -48,16 +116,39 @@ pub(crate) enum ProgramState {
 pub(crate) struct User {
     pub(crate) entity: Entity,
     pub(crate) _name: String,
-    pub(crate) last_message_time: Instant,
+    /// Elapsed app time the user last sent a message
+    pub(crate) last_message_time: Duration,
 }
 /// Marker component to identify user entities
 #[derive(Component)]
-pub(crate) struct UserMarker {}
+pub struct UserMarker {}
 
 /// Component to store the user's Twitch details
 #[derive(Component)]
 pub(crate) struct UserDetails {
-    pub(crate) _name: String,
+    /// Display name
+    pub(crate) name: String,
+    /// Readable color for the user's name
+    pub(crate) color: Color,
+    pub(crate) badges: Vec<ChatBadge>,
+}
+
+/// Component for the name shown under an avatar
+#[derive(Component)]
+pub(crate) struct NameTag {
+    /// How far the avatar was raised to make room for the name
+    pub(crate) height: f32,
+    /// Whether badges are shown next to the name
+    pub(crate) badges: bool,
+    /// Whether the name was centred using the message font rather than estimated widths
+    pub(crate) font_loaded: bool,
+}
+
+/// Component for a badge image shown next to a name
+#[derive(Component)]
+pub(crate) struct BadgeIcon {
+    /// Local image to show if the image cannot be loaded from the badge url
+    pub(crate) fallback: Option<String>,
 }
 
 /// Emum representing possible actions for a user
@@ -73,7 +164,7 @@ pub(crate) enum UserAction {
 #[derive(Component)]
 pub(crate) struct UserActionDetails {
     pub(crate) last_action: UserAction,
-    pub(crate) time: Instant,
+    pub(crate) time: Duration,
 }
 
 /// Bundle used to easily create a new user entity
@@ -85,12 +176,29 @@ pub(crate) struct UserBundle {
     pub(crate) last_action: UserActionDetails,
 }
 
-// MessageSpawnTime
+/// Marker component for the status text shown in the corner of the screen
+#[derive(Component)]
+pub(crate) struct StatusIndicator {}
+
+/// Text of a displayed message and who sent it, kept so the message can be laid out again
+#[derive(Component, Clone)]
+pub struct MessageText {
+    /// Platform id of the message, used to remove it if a moderator deletes it
+    pub(crate) id: Option<String>,
+    pub(crate) text: String,
+    /// Characters of the text replaced by chat emotes, and the id of each emote
+    pub(crate) emotes: Vec<(Range<usize>, String)>,
+    /// Display name of the sender
+    pub(crate) sender: String,
+    pub(crate) sender_color: Color,
+}
+
+// MessageSpawnTime, as elapsed app time
 #[derive(Component)]
-pub(crate) struct MessageSpawnTime(pub(crate) Instant);
+pub(crate) struct MessageSpawnTime(pub(crate) Duration);
 
-impl From<Instant> for MessageSpawnTime {
-    fn from(time: Instant) -> Self {
+impl From<Duration> for MessageSpawnTime {
+    fn from(time: Duration) -> Self {
         Self(time)
     }
 }
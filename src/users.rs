@@ -1,10 +1,11 @@
-use std::time::Instant;
+use std::time::Duration;
 
 use bevy::{
     asset::AssetServer,
     math::{Rect, Vec3},
     prelude::{
-        default, Camera, Commands, DespawnRecursiveExt, Entity, Query, Res, ResMut, Transform, Visibility, With
+        default, Commands, DespawnRecursiveExt, Entity, Query, Res, ResMut, Transform, Visibility,
+        With,
     },
     sprite::{Sprite, SpriteBundle},
     time::Time,
@@ -13,20 +14,26 @@ use log::info;
 use rand::Rng;
 
 use crate::{
-    config::Config, AdjustScale, AppState, TwitchMessage, UserAction, UserActionDetails, UserBundle, UserDetails, UserMarker
+    chat::ChatMessage, config::Config, names::readable_name_color, AdjustScale, AppState,
+    ScreenRect, SeededRng, UserAction, UserActionDetails, UserBundle, UserDetails, UserMarker,
 };
 
+/// Height avatar images are scaled to
+pub(crate) const AVATAR_HEIGHT: f32 = 46.0;
+
 /// Spawn a new user entity in a random position
 pub(crate) fn spawn_user(
     commands: &mut Commands,
     asset_server: &Res<AssetServer>,
-    twitch_message: &TwitchMessage,
+    chat_message: &ChatMessage,
     config: &Config,
+    rng: &mut SeededRng,
     rect: Rect,
+    now: Duration,
 ) -> Entity {
-    info!("New user: {}", twitch_message.user);
+    info!("New user: {}", chat_message.user);
     let translation = Vec3::new(
-        rand::thread_rng().gen_range((rect.max.x / -3.0)..(rect.max.x / 3.0)),
+        rng.0.gen_range((rect.max.x / -3.0)..(rect.max.x / 3.0)),
         -(rect.max.y / 2.0) + 25.0,
         0.0,
     );
@@ -34,10 +41,12 @@ pub(crate) fn spawn_user(
     // Otherwise, use the same avatar for all users
     let avatar_url = if config.random_avatars {
         let avatar_files = std::fs::read_dir("assets/avatars").unwrap();
-        let avatar_files: Vec<String> = avatar_files
+        let mut avatar_files: Vec<String> = avatar_files
             .map(|entry| entry.unwrap().file_name().into_string().unwrap())
             .collect();
-        let random_avatar = rand::thread_rng().gen_range(0..avatar_files.len());
+        // Directory order is platform dependent, sort so seeded runs pick the same avatars
+        avatar_files.sort();
+        let random_avatar = rng.0.gen_range(0..avatar_files.len());
         format!("avatars/{}", avatar_files[random_avatar])
     } else {
         config.avatar_url.clone()
@@ -46,7 +55,9 @@ pub(crate) fn spawn_user(
         .spawn(UserBundle {
             marker: UserMarker {},
             details: UserDetails {
-                _name: twitch_message.user.clone(),
+                name: chat_message.user.clone(),
+                color: readable_name_color(chat_message.name_color, &chat_message.user),
+                badges: chat_message.badges.clone(),
             },
             sprite: SpriteBundle {
                 texture: asset_server.load(&avatar_url),
@@ -56,23 +67,24 @@ pub(crate) fn spawn_user(
             },
             last_action: UserActionDetails {
                 last_action: UserAction::Stop,
-                time: Instant::now(),
+                time: now,
             },
-        }).insert(AdjustScale{})
+        })
+        .insert(AdjustScale {})
         .id()
 }
 
 // Move avatars left and right randomly
 pub(crate) fn move_users(
     mut user_query: Query<(&mut Transform, &mut Sprite, &mut UserActionDetails), With<UserMarker>>,
-    camera_query: Query<&Camera>,
+    screen_rect: Res<ScreenRect>,
     time: Res<Time>,
     config: Res<Config>,
+    mut rng: ResMut<SeededRng>,
 ) {
-    let mut rng = rand::thread_rng();
-    let rect = camera_query.single().logical_viewport_rect().unwrap();
+    let rect = screen_rect.0;
     for (mut transform, mut sprite, mut action) in user_query.iter_mut() {
-        let now = Instant::now();
+        let now = time.elapsed();
         let delta = time.delta_seconds();
 
         let wait_duration = match action.last_action {
@@ -80,13 +92,15 @@ pub(crate) fn move_users(
             _ => config.action_duration,
         };
         // Check if it's time to change the action
-        if now.duration_since(action.time) > wait_duration {
+        if now.saturating_sub(action.time) > wait_duration {
             // Check if the user is close to the left edge
-            let close_to_left_edge = transform.translation.x <= (rect.max.x / -2.0) + config.edge_buffer;
+            let close_to_left_edge =
+                transform.translation.x <= (rect.max.x / -2.0) + config.edge_buffer;
             // Check if the user is close to the right edge
-            let close_to_right_edge = transform.translation.x >= (rect.max.x / 2.0) - config.edge_buffer;
+            let close_to_right_edge =
+                transform.translation.x >= (rect.max.x / 2.0) - config.edge_buffer;
 
-            action.last_action = match rng.gen_range(0..3) {
+            action.last_action = match rng.0.gen_range(0..3) {
                 0 if close_to_left_edge => UserAction::MoveRight,
                 1 if close_to_right_edge => UserAction::MoveLeft,
                 0 => UserAction::MoveLeft,
@@ -113,15 +127,20 @@ pub(crate) fn move_users(
 }
 
 // Check if avatars need to despawn due to inactivity
-pub(crate) fn despawn_users(mut commands: Commands, mut app_state: ResMut<AppState>, config: Res<Config>) {
-    let now = Instant::now();
-    app_state.active_users.retain(|user_name, user| {
-        if now.duration_since(user.last_message_time) > config.user_despawn_time {
-            info!("Despawning user: {}", user_name);
+pub(crate) fn despawn_users(
+    mut commands: Commands,
+    mut app_state: ResMut<AppState>,
+    config: Res<Config>,
+    time: Res<Time>,
+) {
+    let now = time.elapsed();
+    app_state.active_users.retain(|_, user| {
+        if now.saturating_sub(user.last_message_time) > config.user_despawn_time {
+            info!("Despawning user: {}", user._name);
             commands.entity(user.entity).despawn_recursive();
             false
         } else {
             true
         }
     });
-}
\ No newline at end of file
+}
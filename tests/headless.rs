@@ -0,0 +1,139 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::{App, Transform, With};
+use minawan_watch_party::{build_app, MessageText, UserMarker};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::runtime::Runtime;
+
+const SEED: u64 = 7;
+/// Frames per second of headless runs
+const FPS: u32 = 60;
+
+/// Write a config playing back `replay` in headless mode, returning its path
+fn write_config(name: &str, replay: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("minawan-headless-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let replay_path = dir.join("replay.jsonl");
+    std::fs::write(&replay_path, replay).unwrap();
+    let config_path = dir.join("config.ini");
+    std::fs::write(
+        &config_path,
+        format!(
+            "[Channel]\n\
+             CHANNEL_NAME = minawan\n\
+             [General]\n\
+             HEADLESS = true\n\
+             SEED = {}\n\
+             [Avatars]\n\
+             SHOW_NAMES = false\n\
+             USER_DESPAWN_TIME_SECS = 4\n\
+             [Messages]\n\
+             MESSAGE_DESPAWN_TIME_MILIS = 2000\n\
+             [Replay]\n\
+             FILE = {}\n",
+            SEED,
+            replay_path.display()
+        ),
+    )
+    .unwrap();
+    config_path
+}
+
+fn replay_line(user_id: &str, user: &str, message: &str) -> String {
+    format!(
+        "{{\"timestamp\":0,\"user_id\":\"{}\",\"user\":\"{}\",\"message\":\"{}\"}}\n",
+        user_id, user, message
+    )
+}
+
+fn avatar_positions(app: &mut App) -> Vec<(f32, f32)> {
+    app.world_mut()
+        .query_filtered::<&Transform, With<UserMarker>>()
+        .iter(app.world())
+        .map(|transform| (transform.translation.x, transform.translation.y))
+        .collect()
+}
+
+fn message_count(app: &mut App) -> usize {
+    app.world_mut()
+        .query::<&MessageText>()
+        .iter(app.world())
+        .count()
+}
+
+/// Step the app until the replayed messages have arrived and an avatar is on screen
+fn wait_for_avatar(app: &mut App) {
+    let start = Instant::now();
+    while avatar_positions(app).is_empty() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "No avatar was spawned"
+        );
+        app.update();
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Step the app through `seconds` of simulated time
+fn run_for(app: &mut App, seconds: f32) {
+    for _ in 0..(seconds * FPS as f32).round() as u32 {
+        app.update();
+    }
+}
+
+#[test]
+fn avatars_and_messages_despawn_after_their_configured_times() {
+    let runtime = Runtime::new().unwrap();
+    let _guard = runtime.enter();
+    let config = write_config("despawn", &replay_line("1", "Minawan", "hello"));
+    let mut app = build_app(config.display().to_string(), vec![]).unwrap();
+
+    wait_for_avatar(&mut app);
+    // The first random number picks where the avatar spawns on the default 1920x1080 screen
+    let x = StdRng::seed_from_u64(SEED).gen_range((1920.0 / -3.0)..(1920.0 / 3.0));
+    assert_eq!(
+        avatar_positions(&mut app),
+        vec![(x, -(1080.0 / 2.0) + 25.0)]
+    );
+    assert_eq!(message_count(&mut app), 1);
+
+    run_for(&mut app, 1.9);
+    assert_eq!(message_count(&mut app), 1);
+    run_for(&mut app, 0.2);
+    assert_eq!(message_count(&mut app), 0);
+    assert_eq!(avatar_positions(&mut app).len(), 1);
+
+    run_for(&mut app, 1.8);
+    assert_eq!(avatar_positions(&mut app).len(), 1);
+    run_for(&mut app, 0.2);
+    assert!(avatar_positions(&mut app).is_empty());
+}
+
+#[test]
+fn seeded_runs_move_avatars_the_same_way() {
+    let runtime = Runtime::new().unwrap();
+    let _guard = runtime.enter();
+
+    let mut paths = vec![];
+    for run in ["first", "second"] {
+        let config = write_config(
+            &format!("repeat-{}", run),
+            &replay_line("1", "Minawan", "hi"),
+        );
+        let mut app = build_app(config.display().to_string(), vec![]).unwrap();
+        wait_for_avatar(&mut app);
+        let mut path = vec![];
+        for _ in 0..8 {
+            run_for(&mut app, 0.5);
+            path.extend(avatar_positions(&mut app));
+        }
+        paths.push(path);
+    }
+    assert_eq!(paths[0], paths[1]);
+    // The avatar walks around rather than standing still
+    assert!(paths[0].iter().any(|position| *position != paths[0][0]));
+}